@@ -1,16 +1,18 @@
-use anyhow::Result;
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
+#[cfg(feature = "rocksdb-storage")]
 use mktemp::Temp;
 
-use xq::{parser, run_command, storage::Storage, types::*};
-
-fn criterion_benchmark(c: &mut Criterion) -> Result<()> {
-    let path = Temp::new_dir()?.to_path_buf().display().to_string();
+use xq::{environment::Environment, parser, run_command, storage::Storage, types::*};
 
+fn criterion_benchmark(c: &mut Criterion) {
     #[cfg(feature = "memory-storage")]
     let storage = Storage::new();
     #[cfg(feature = "rocksdb-storage")]
-    let storage = Storage::init(&path)?;
+    let storage = {
+        let path = Temp::new_dir().unwrap().to_path_buf().display().to_string();
+        Storage::init(&path).unwrap()
+    };
+    let mut env = Environment::new();
 
     c.bench_function("parsing", |b| {
         b.iter(|| {
@@ -22,47 +24,50 @@ fn criterion_benchmark(c: &mut Criterion) -> Result<()> {
     });
 
     c.bench_function("enqueue", |b| {
-        b.iter(|| run_command(&storage, black_box(Command::enqueue("a", 1))).unwrap())
+        b.iter(|| run_command(&storage, &mut env, black_box(Command::enqueue("a", 1))).unwrap())
     });
 
     c.bench_function("multiple peeks", |b| {
         b.iter(|| {
-            run_command(&storage, black_box(Command::enqueue("multipeeks", 1))).unwrap();
+            run_command(
+                &storage,
+                &mut env,
+                black_box(Command::enqueue("multipeeks", 1)),
+            )
+            .unwrap();
 
             for _ in 1..100 {
-                run_command(&storage, black_box(Command::peek("multipeeks"))).unwrap();
+                run_command(&storage, &mut env, black_box(Command::peek("multipeeks"))).unwrap();
             }
         });
     });
 
     c.bench_function("enqueue + dequeue", |b| {
         b.iter(|| {
-            run_command(&storage, black_box(Command::enqueue("b", 1))).unwrap();
-            run_command(&storage, black_box(Command::dequeue("b"))).unwrap();
+            run_command(&storage, &mut env, black_box(Command::enqueue("b", 1))).unwrap();
+            run_command(&storage, &mut env, black_box(Command::dequeue("b"))).unwrap();
         })
     });
 
     c.bench_function("enqueue * 100 + dequeue", |b| {
         b.iter(|| {
             for _ in 1..100 {
-                run_command(&storage, black_box(Command::enqueue("b", 1))).unwrap();
+                run_command(&storage, &mut env, black_box(Command::enqueue("b", 1))).unwrap();
             }
 
-            run_command(&storage, black_box(Command::dequeue("b"))).unwrap();
+            run_command(&storage, &mut env, black_box(Command::dequeue("b"))).unwrap();
         })
     });
 
     c.bench_function("enqueue * 1000 + dequeue", |b| {
         b.iter(|| {
             for _ in 1..1000 {
-                run_command(&storage, black_box(Command::enqueue("b", 1))).unwrap();
+                run_command(&storage, &mut env, black_box(Command::enqueue("b", 1))).unwrap();
             }
 
-            run_command(&storage, black_box(Command::dequeue("b"))).unwrap();
+            run_command(&storage, &mut env, black_box(Command::dequeue("b"))).unwrap();
         })
     });
-
-    Ok(())
 }
 
 criterion_group!(benches, criterion_benchmark);
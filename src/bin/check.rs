@@ -0,0 +1,148 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::Result;
+use structopt::StructOpt;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tracing::{debug, info, trace};
+
+use xq::{
+    check::{self, History},
+    types::*,
+};
+
+#[derive(Clone, Debug, StructOpt)]
+pub struct Options {
+    /// Addresses of the nodes to run clients against, used round-robin
+    #[structopt(name = "ADDRESS", required = true)]
+    addrs: Vec<SocketAddr>,
+    #[structopt(short = "c", long = "clients", default_value = "4")]
+    clients: usize,
+    #[structopt(short = "n", long = "operations", default_value = "100")]
+    operations: usize,
+    #[structopt(short = "k", long = "keys", default_value = "2")]
+    keys: usize,
+    #[structopt(short = "s", long = "seed", default_value = "1")]
+    seed: u64,
+}
+
+/// xorshift64, enough to spread operations around without a dependency.
+fn next(seed: &mut u64) -> u64 {
+    *seed ^= *seed << 13;
+    *seed ^= *seed >> 7;
+    *seed ^= *seed << 17;
+    *seed
+}
+
+fn parse_output(line: &str) -> Option<Value> {
+    match line {
+        "OK" => None,
+        "null" => Some(Value::Null),
+        _ => Some(
+            line.parse::<i64>()
+                .map(Value::Integer)
+                .unwrap_or_else(|_| Value::String(line.to_string())),
+        ),
+    }
+}
+
+/// The checker assumes every queue starts empty, so drain whatever a previous
+/// run left behind before recording anything.
+async fn drain(addr: SocketAddr, keys: usize) -> Result<()> {
+    let socket = TcpStream::connect(addr).await?;
+    let (reader, mut writer) = socket.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    for key in 0..keys {
+        loop {
+            writer
+                .write_all(format!("dequeue check_{}\n", key).as_bytes())
+                .await?;
+
+            match lines.next_line().await? {
+                Some(line) if line == "null" => break,
+                Some(_) => continue,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[tracing::instrument(skip(history))]
+async fn run_client(
+    addr: SocketAddr,
+    client: usize,
+    options: Options,
+    history: Arc<History>,
+) -> Result<()> {
+    let socket = TcpStream::connect(addr).await?;
+    socket.set_nodelay(true)?;
+
+    let (reader, mut writer) = socket.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let mut seed = options.seed.wrapping_add(client as u64 + 1) | 1;
+
+    for i in 0..options.operations {
+        let key = format!("check_{}", next(&mut seed) % options.keys as u64);
+        let command = match next(&mut seed) % 4 {
+            0 | 1 => Command::enqueue(key, (client * options.operations + i) as i64),
+            2 => Command::dequeue(key),
+            _ => match next(&mut seed) % 2 {
+                0 => Command::peek(key),
+                _ => Command::length(key),
+            },
+        };
+
+        let source = format!("{}\n", command);
+
+        let id = history.invoke(client, command)?;
+        writer.write_all(source.as_bytes()).await?;
+
+        match lines.next_line().await? {
+            Some(line) if line.starts_with("ERROR") => {
+                debug!(client, line = %line, "Operation failed");
+                return Ok(());
+            }
+            Some(line) => history.complete(id, parse_output(&line))?,
+            None => return Ok(()),
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing::subscriber::set_global_default(tracing_subscriber::FmtSubscriber::new())?;
+    trace!("Started subscriber for tracing");
+
+    let options = Options::from_args();
+    let history = Arc::new(History::new());
+
+    drain(options.addrs[0], options.keys).await?;
+
+    let clients = (0..options.clients)
+        .map(|client| {
+            let addr = options.addrs[client % options.addrs.len()];
+            tokio::spawn(run_client(addr, client, options.clone(), history.clone()))
+        })
+        .collect::<Vec<_>>();
+
+    for client in futures::future::join_all(clients).await {
+        if let Err(e) = client? {
+            debug!(error = %e, "Client stopped early");
+        }
+    }
+
+    let operations = history.operations()?;
+    info!(operations = operations.len(), "Checking history");
+
+    check::check(&operations)?;
+
+    info!("History is linearizable");
+
+    Ok(())
+}
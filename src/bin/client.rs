@@ -1,12 +1,18 @@
-use std::{env, error::Error, net::SocketAddr};
+use std::{env, error::Error, fs, io::Read, net::SocketAddr};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use bytes::Bytes;
 use futures::{future, Sink, SinkExt, Stream, StreamExt};
-use tokio::{io, net::TcpStream};
+use tokio::{
+    io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
 use tokio_util::codec::{BytesCodec, FramedRead, FramedWrite};
 
-use xq::errors::*;
+use xq::{errors::*, parser, types::*};
+
+/// How many values `xq range` fetches from the server at a time.
+const DEFAULT_PAGE_SIZE: usize = 50;
 
 async fn connect(
     addr: &SocketAddr,
@@ -33,10 +39,114 @@ async fn connect(
     }
 }
 
+/// Reformats the given .xq files in place, or stdin to stdout when no files
+/// are given. With `--check`, lists the files that need formatting instead.
+fn fmt(args: &[String]) -> Result<()> {
+    let check = args.iter().any(|arg| arg == "--check");
+    let files = args
+        .iter()
+        .filter(|arg| *arg != "--check")
+        .collect::<Vec<_>>();
+
+    if files.is_empty() {
+        let mut input = String::new();
+        std::io::stdin().read_to_string(&mut input)?;
+        print!("{}", parser::format(&input)?);
+
+        return Ok(());
+    }
+
+    let mut unformatted = vec![];
+
+    for file in files {
+        let contents = fs::read_to_string(file)?;
+        let formatted = parser::format(&contents)?;
+
+        if formatted == contents {
+            continue;
+        }
+
+        if check {
+            unformatted.push(file.as_str());
+        } else {
+            fs::write(file, formatted)?;
+        }
+    }
+
+    if !unformatted.is_empty() {
+        bail!(ClientError::Unformatted(unformatted.join(", ")));
+    }
+
+    Ok(())
+}
+
+/// Prints every value of a queue, one per line, fetching them a page at a
+/// time so that long queues don't have to fit in a single reply.
+///
+/// Pages are read separately, so values enqueued or dequeued while paging
+/// may be skipped or printed twice.
+async fn range(args: &[String]) -> Result<()> {
+    let (addr, key) = match args {
+        [addr, key, ..] => (addr.parse::<SocketAddr>()?, key),
+        _ => bail!("Usage: xq range ADDRESS KEY [PAGE_SIZE]"),
+    };
+    let page_size = match args.get(2) {
+        Some(size) => size.parse::<usize>()?,
+        None => DEFAULT_PAGE_SIZE,
+    };
+
+    let stream = TcpStream::connect(addr)
+        .await
+        .map_err(|_| ClientError::ConnectionError)?;
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let mut start = 0;
+
+    loop {
+        let command = format!("range {} {} {}\n", key, start, page_size);
+        writer.write_all(command.as_bytes()).await?;
+
+        let reply = match lines.next_line().await? {
+            Some(reply) => reply,
+            None => bail!(ClientError::ConnectionError),
+        };
+
+        if let Some(error) = reply.strip_prefix("ERROR: ") {
+            bail!("{}", error);
+        }
+
+        let values = match parser::parse_value(&reply)? {
+            Value::List(values) => values,
+            value => bail!(DataError::UnexpectedValue {
+                expected: String::from("a list"),
+                got: Literal(&value).to_string(),
+            }),
+        };
+
+        for value in &values {
+            println!("{}", Literal(value));
+        }
+
+        if values.len() < page_size || page_size == 0 {
+            return Ok(());
+        }
+
+        start += values.len();
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = env::args().skip(1).collect::<Vec<_>>();
 
+    if args.first().map(String::as_str) == Some("fmt") {
+        return fmt(&args[1..]);
+    }
+
+    if args.first().map(String::as_str) == Some("range") {
+        return range(&args[1..]).await;
+    }
+
     let addr = args.first().expect("Failed to get addr");
     let addr = addr.parse::<SocketAddr>()?;
 
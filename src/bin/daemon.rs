@@ -1,44 +1,92 @@
-use std::{fmt::Debug, str};
+use std::fmt::Debug;
 
 use anyhow::Result;
 use structopt::StructOpt;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::net::{TcpListener, TcpStream};
 use tracing::{debug, info, trace};
 
 use xq::{
-    parser, run_command,
-    storage::{Storage, StorageBackend, StorageOptions},
+    environment::Environment,
+    errors::ReplicationError,
+    parser,
+    replication::{self, ReplicatedStorage},
+    run_command,
+    storage::{Storage, StorageBackend},
 };
 
+#[cfg(feature = "rocksdb-storage")]
+use xq::storage::StorageOptions;
+
 #[derive(Clone, Debug, StructOpt)]
 pub struct Options {
     #[structopt(name = "ADDRESS", default_value = "0.0.0.0:8080")]
     addr: String,
+    /// Run as a read-only replica of the primary at this address
+    #[structopt(long = "replica-of")]
+    replica_of: Option<String>,
+    /// Stream snapshots and mutations to replicas that ask for them with
+    /// `replicate`
+    #[structopt(long = "allow-replication")]
+    allow_replication: bool,
+    /// Let clients turn this replica into a primary with `promote`
+    #[structopt(long = "allow-promote")]
+    allow_promote: bool,
+    #[cfg(feature = "rocksdb-storage")]
     #[structopt(flatten)]
     storage: StorageOptions,
 }
 
 #[tracing::instrument]
 async fn run_server<T: StorageBackend + Send + Sync + Debug>(
-    mut socket: TcpStream,
-    storage: T,
+    socket: TcpStream,
+    storage: ReplicatedStorage<T>,
+    options: Options,
 ) -> Result<()> {
-    let mut buf = vec![0; 1024];
+    let (reader, mut writer) = socket.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let mut env = Environment::new();
 
-    loop {
-        let _ = socket.read(&mut buf).await?;
+    while let Some(line) = lines.next_line().await? {
+        // Replication and promotion hand out or take over the whole storage,
+        // so they are only served when the daemon was started to allow them.
+        let disabled = match line.trim() {
+            "" => continue,
+            "replicate" if options.allow_replication => {
+                return replication::serve_replica(writer, storage).await
+            }
+            "promote" if options.allow_promote => {
+                storage.promote();
+                writer.write_all(b"OK\n").await?;
+                continue;
+            }
+            "replicate" => Some(ReplicationError::NotEnabled("Replication")),
+            "promote" => Some(ReplicationError::NotEnabled("Promotion")),
+            _ => None,
+        };
 
-        match parser::parse(&str::from_utf8(&buf)?) {
+        if let Some(e) = disabled {
+            writer
+                .write_all(format!("ERROR: {}\n", e).as_bytes())
+                .await?;
+            continue;
+        }
+
+        match parser::parse(&line) {
             Ok(commands) => {
                 for command in commands {
                     debug!(command = ?&command, "Running command");
 
-                    match run_command(&storage, command) {
-                        Ok(Some(v)) => socket.write_all(format!("{}\n", v).as_bytes()).await?,
-                        Ok(None) => socket.write_all(b"OK\n").await?,
+                    // Commands like `move ... wait` may block, so they must
+                    // not hold up the other connections on this thread.
+                    let result =
+                        tokio::task::block_in_place(|| run_command(&storage, &mut env, command));
+
+                    match result {
+                        Ok(Some(v)) => writer.write_all(format!("{}\n", v).as_bytes()).await?,
+                        Ok(None) => writer.write_all(b"OK\n").await?,
                         Err(e) => {
-                            socket
+                            writer
                                 .write_all(format!("ERROR: {}\n", e).as_bytes())
                                 .await?
                         }
@@ -46,12 +94,14 @@ async fn run_server<T: StorageBackend + Send + Sync + Debug>(
                 }
             }
             Err(e) => {
-                socket
+                writer
                     .write_all(format!("ERROR: {}\n", e).as_bytes())
                     .await?
             }
         }
     }
+
+    Ok(())
 }
 
 #[tokio::main]
@@ -66,12 +116,21 @@ async fn main() -> Result<()> {
     #[cfg(feature = "rocksdb-storage")]
     let storage = Storage::init(&options.storage.database_path)?;
 
+    let storage = match &options.replica_of {
+        Some(primary) => {
+            let storage = ReplicatedStorage::replica(storage);
+            tokio::spawn(replication::follow(primary.clone(), storage.clone()));
+            storage
+        }
+        None => ReplicatedStorage::primary(storage),
+    };
+
     let listener = TcpListener::bind(&options.addr).await?;
 
     info!(address = %&options.addr, "Daemon started");
 
     loop {
         let (socket, _) = listener.accept().await?;
-        tokio::spawn(run_server(socket, storage.clone()));
+        tokio::spawn(run_server(socket, storage.clone(), options.clone()));
     }
 }
@@ -5,15 +5,16 @@ use anyhow::Result;
 use structopt::StructOpt;
 use tracing::{debug, info, trace};
 
-use xq::{
-    parser, run_command,
-    storage::{Storage, StorageOptions},
-};
+use xq::{environment::Environment, parser, run_command, storage::Storage};
+
+#[cfg(feature = "rocksdb-storage")]
+use xq::storage::StorageOptions;
 
 #[derive(Clone, Debug, StructOpt)]
 pub struct Options {
     #[structopt(name = "FILE")]
     file: PathBuf,
+    #[cfg(feature = "rocksdb-storage")]
     #[structopt(flatten)]
     storage: StorageOptions,
 }
@@ -36,10 +37,11 @@ async fn main() -> Result<()> {
 
     trace!(program = ?&contents, "Running program");
     let commands = parser::parse(&contents)?;
+    let mut env = Environment::new();
 
     for command in commands {
         debug!(command = ?&command, "Running command");
-        let _ = run_command(&storage, command)?;
+        let _ = run_command(&storage, &mut env, command)?;
     }
 
     info!("Test finished successfully");
@@ -0,0 +1,413 @@
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use anyhow::{bail, Result};
+
+use crate::errors::*;
+use crate::types::*;
+
+/// A single client operation, as observed from outside the server.
+///
+/// `call` and `ret` are timestamps on a shared clock. An operation without a
+/// `ret` never completed (the client crashed or timed out), so it may or may
+/// not have taken effect and its `output` is ignored.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Operation {
+    pub client: usize,
+    pub command: Command,
+    pub output: Option<Value>,
+    pub call: u64,
+    pub ret: Option<u64>,
+}
+
+impl Operation {
+    fn key(&self) -> Option<&Identifier> {
+        match &self.command {
+            Command::Enqueue {
+                queue: key,
+                value: Expr::Value(_),
+                headers: None,
+                dedup: None,
+                group: None,
+                wait: None,
+            }
+            | Command::Dequeue(key, Reply::Value)
+            | Command::Length(key)
+            | Command::Peek(key, Reply::Value) => Some(key),
+            _ => None,
+        }
+    }
+}
+
+/// Records a concurrent history of operations from many clients.
+#[derive(Debug)]
+pub struct History {
+    start: Instant,
+    operations: Mutex<Vec<Operation>>,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            operations: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_nanos() as u64
+    }
+
+    /// Records the invocation of `command`, returning a handle to complete it.
+    pub fn invoke(&self, client: usize, command: Command) -> Result<usize> {
+        let call = self.now();
+        let mut operations = self
+            .operations
+            .lock()
+            .map_err(|_| StorageError::FailedLock)?;
+
+        operations.push(Operation {
+            client,
+            command,
+            output: None,
+            call,
+            ret: None,
+        });
+
+        Ok(operations.len() - 1)
+    }
+
+    /// Records the completion of a previously invoked operation.
+    pub fn complete(&self, id: usize, output: Option<Value>) -> Result<()> {
+        let ret = self.now();
+        let mut operations = self
+            .operations
+            .lock()
+            .map_err(|_| StorageError::FailedLock)?;
+
+        if let Some(op) = operations.get_mut(id) {
+            op.output = output;
+            op.ret = Some(ret);
+        }
+
+        Ok(())
+    }
+
+    pub fn operations(&self) -> Result<Vec<Operation>> {
+        let operations = self
+            .operations
+            .lock()
+            .map_err(|_| StorageError::FailedLock)?;
+        Ok(operations.clone())
+    }
+}
+
+/// Applies `op` to a sequential FIFO queue, returning the new state if the
+/// observed output is consistent with it.
+fn step(state: &VecDeque<Value>, op: &Operation) -> Option<VecDeque<Value>> {
+    let head = state.front().cloned().unwrap_or(Value::Null);
+    let known = op.ret.is_some();
+
+    match &op.command {
+        Command::Enqueue {
+            value: Expr::Value(value),
+            headers: None,
+            dedup: None,
+            group: None,
+            wait: None,
+            ..
+        } => {
+            // An enqueue replies with the id of its message.
+            if known && !matches!(op.output, Some(Value::Integer(_))) {
+                return None;
+            }
+
+            let mut next = state.clone();
+            next.push_back(value.clone());
+            Some(next)
+        }
+        Command::Dequeue(_, Reply::Value) => {
+            if known && op.output.as_ref() != Some(&head) {
+                return None;
+            }
+
+            let mut next = state.clone();
+            next.pop_front();
+            Some(next)
+        }
+        Command::Peek(_, Reply::Value) => {
+            if known && op.output.as_ref() != Some(&head) {
+                return None;
+            }
+
+            Some(state.clone())
+        }
+        Command::Length(_) => {
+            if known && op.output != Some(Value::Integer(state.len() as i64)) {
+                return None;
+            }
+
+            Some(state.clone())
+        }
+        _ => None,
+    }
+}
+
+fn is_read_only(op: &Operation) -> bool {
+    matches!(
+        op.command,
+        Command::Peek(_, Reply::Value) | Command::Length(_)
+    )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Event {
+    Call(usize),
+    Return(usize),
+}
+
+/// A point in the search: how far into the history we are, the state of the
+/// queue and which operations were already linearized.
+#[derive(Clone)]
+struct Config {
+    event: usize,
+    state: VecDeque<Value>,
+    done: Vec<u64>,
+}
+
+impl Config {
+    fn is_done(&self, op: usize) -> bool {
+        self.done[op / 64] & (1 << (op % 64)) != 0
+    }
+
+    fn linearize(&self, op: usize, state: VecDeque<Value>) -> Self {
+        let mut next = Self {
+            event: self.event,
+            state,
+            done: self.done.clone(),
+        };
+
+        next.done[op / 64] |= 1 << (op % 64);
+        next
+    }
+
+    fn key(&self) -> Result<(usize, Vec<u64>, Vec<u8>)> {
+        Ok((
+            self.event,
+            self.done.clone(),
+            bincode::serialize(&self.state)?,
+        ))
+    }
+}
+
+/// Checks a single-queue history against a sequential FIFO queue.
+///
+/// This is a depth-first search over the possible linearizations, using the
+/// just-in-time linearization and memoization from Lowe's "Testing for
+/// linearizability": operations are only linearized when the return of an
+/// operation forces it, which keeps the search small for concurrent writers.
+fn check_queue(ops: &[Operation]) -> Result<bool> {
+    let mut events = Vec::with_capacity(ops.len() * 2);
+
+    for (i, op) in ops.iter().enumerate() {
+        events.push((op.call, Event::Call(i)));
+
+        if let Some(ret) = op.ret {
+            events.push((ret, Event::Return(i)));
+        }
+    }
+
+    events.sort();
+
+    let mut called = vec![0; ops.len()];
+    for (position, (_, event)) in events.iter().enumerate() {
+        if let Event::Call(i) = event {
+            called[*i] = position;
+        }
+    }
+
+    let mut seen = HashSet::new();
+    let mut stack = vec![Config {
+        event: 0,
+        state: VecDeque::new(),
+        done: vec![0; ops.len() / 64 + 1],
+    }];
+
+    while let Some(mut config) = stack.pop() {
+        // Calls and returns of operations that are already linearized don't
+        // require any choice, so skip ahead to the next forced linearization.
+        let forced = loop {
+            match events.get(config.event) {
+                Some((_, Event::Return(i))) if !config.is_done(*i) => break Some(*i),
+                Some(_) => config.event += 1,
+                None => break None,
+            }
+        };
+
+        let forced = match forced {
+            Some(i) => i,
+            None => return Ok(true),
+        };
+
+        let pending = (0..ops.len())
+            .filter(|&i| called[i] < config.event && !config.is_done(i))
+            .filter_map(|i| step(&config.state, &ops[i]).map(|state| (i, state)))
+            .collect::<Vec<_>>();
+
+        // A read that matches the current state can always be linearized
+        // right away: it doesn't change the state and doesn't constrain any
+        // other operation, so there is no need to try the alternatives.
+        let next = match pending.iter().find(|(i, _)| is_read_only(&ops[*i])) {
+            Some((i, state)) => vec![config.linearize(*i, state.clone())],
+            None => pending
+                .into_iter()
+                .map(|(i, state)| config.linearize(i, state))
+                .collect(),
+        };
+
+        // Stack order: try the operation whose return forced us here last,
+        // so it is popped first.
+        let (first, rest): (Vec<_>, Vec<_>) = next.into_iter().partition(|c| c.is_done(forced));
+
+        for candidate in rest.into_iter().chain(first) {
+            if seen.insert(candidate.key()?) {
+                stack.push(candidate);
+            }
+        }
+    }
+
+    Ok(false)
+}
+
+/// Checks that a history of queue operations is linearizable.
+///
+/// Operations on different queues are independent, so the history is split
+/// by key and each queue is checked on its own.
+pub fn check(operations: &[Operation]) -> Result<()> {
+    let mut queues: BTreeMap<&Identifier, Vec<Operation>> = BTreeMap::new();
+
+    for op in operations {
+        match op.key() {
+            Some(key) => queues.entry(key).or_default().push(op.clone()),
+            None => bail!(CheckError::UnsupportedCommand(format!("{:?}", op.command))),
+        }
+    }
+
+    for (key, ops) in queues {
+        if !check_queue(&ops)? {
+            bail!(CheckError::NotLinearizable(key.to_string()));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+fn op(command: Command, output: Option<Value>, call: u64, ret: u64) -> Operation {
+    Operation {
+        client: 0,
+        command,
+        output,
+        call,
+        ret: Some(ret),
+    }
+}
+
+#[test]
+fn sequential_history_is_linearizable() {
+    let history = vec![
+        op(Command::enqueue("a", 1), Some(1.into()), 0, 1),
+        op(Command::enqueue("a", 2), Some(2.into()), 2, 3),
+        op(Command::length("a"), Some(2.into()), 4, 5),
+        op(Command::peek("a"), Some(1.into()), 6, 7),
+        op(Command::dequeue("a"), Some(1.into()), 8, 9),
+        op(Command::dequeue("a"), Some(2.into()), 10, 11),
+        op(Command::dequeue("a"), Some(Value::Null), 12, 13),
+    ];
+
+    assert!(check(&history).is_ok());
+}
+
+#[test]
+fn concurrent_enqueues_can_be_reordered() {
+    let history = vec![
+        op(Command::enqueue("a", 1), Some(1.into()), 0, 10),
+        op(Command::enqueue("a", 2), Some(2.into()), 1, 9),
+        op(Command::dequeue("a"), Some(2.into()), 11, 12),
+        op(Command::dequeue("a"), Some(1.into()), 13, 14),
+    ];
+
+    assert!(check(&history).is_ok());
+}
+
+#[test]
+fn duplicate_dequeue_is_not_linearizable() {
+    let history = vec![
+        op(Command::enqueue("a", 1), Some(1.into()), 0, 1),
+        op(Command::dequeue("a"), Some(1.into()), 2, 5),
+        op(Command::dequeue("a"), Some(1.into()), 3, 6),
+    ];
+
+    assert_eq!(
+        check(&history)
+            .unwrap_err()
+            .downcast::<CheckError>()
+            .unwrap(),
+        CheckError::NotLinearizable("a".into())
+    );
+}
+
+#[test]
+fn lost_enqueue_is_not_linearizable() {
+    let history = vec![
+        op(Command::enqueue("a", 1), Some(1.into()), 0, 1),
+        op(Command::length("a"), Some(0.into()), 2, 3),
+    ];
+
+    assert!(check(&history).is_err());
+}
+
+#[test]
+fn pending_operations_may_or_may_not_take_effect() {
+    let pending = Operation {
+        client: 1,
+        command: Command::enqueue("a", 1),
+        output: None,
+        call: 0,
+        ret: None,
+    };
+
+    let applied = vec![
+        pending.clone(),
+        op(Command::dequeue("a"), Some(1.into()), 2, 3),
+    ];
+    let skipped = vec![pending, op(Command::length("a"), Some(0.into()), 2, 3)];
+
+    assert!(check(&applied).is_ok());
+    assert!(check(&skipped).is_ok());
+}
+
+#[test]
+fn queues_are_checked_independently() {
+    let history = vec![
+        op(Command::enqueue("a", 1), Some(1.into()), 0, 1),
+        op(Command::enqueue("b", 2), Some(2.into()), 0, 1),
+        op(Command::dequeue("b"), Some(2.into()), 2, 3),
+        op(Command::dequeue("a"), Some(2.into()), 2, 3),
+    ];
+
+    assert_eq!(
+        check(&history)
+            .unwrap_err()
+            .downcast::<CheckError>()
+            .unwrap(),
+        CheckError::NotLinearizable("a".into())
+    );
+}
@@ -0,0 +1,84 @@
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Result};
+
+use crate::errors::*;
+use crate::types::*;
+
+/// Variables bound with `let` and the commands of an open transaction,
+/// scoped to a single script or client connection.
+#[derive(Debug, Default, Clone)]
+pub struct Environment {
+    variables: BTreeMap<Identifier, Value>,
+    transaction: Option<Vec<Command>>,
+}
+
+impl Environment {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn get(&self, id: &Identifier) -> Result<Value> {
+        match self.variables.get(id) {
+            Some(v) => Ok(v.clone()),
+            None => Err(DataError::UndefinedVariable(id.to_string()).into()),
+        }
+    }
+
+    pub fn set(&mut self, id: Identifier, value: Value) {
+        self.variables.insert(id, value);
+    }
+
+    pub fn begin(&mut self) -> Result<()> {
+        if self.transaction.is_some() {
+            bail!(TransactionError::AlreadyStarted);
+        }
+
+        self.transaction = Some(vec![]);
+        Ok(())
+    }
+
+    /// The commands buffered so far, if a transaction is open.
+    pub fn transaction_mut(&mut self) -> Option<&mut Vec<Command>> {
+        self.transaction.as_mut()
+    }
+
+    /// Closes the open transaction, returning its commands.
+    pub fn end(&mut self) -> Result<Vec<Command>> {
+        match self.transaction.take() {
+            Some(commands) => Ok(commands),
+            None => bail!(TransactionError::NotStarted),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "memory-storage"))]
+#[test]
+fn variables_are_bound_by_let() -> Result<()> {
+    use crate::{parser, run_command, storage::Storage};
+
+    let storage = Storage::new();
+    let mut env = Environment::new();
+    let program = "enqueue a 1\nlet x = (dequeue a)\nenqueue b $x\nassert (peek b) $x";
+
+    for command in parser::parse(program)? {
+        run_command(&storage, &mut env, command)?;
+    }
+
+    assert_eq!(env.get(&"x".into())?, 1.into());
+    assert!(run_command(
+        &storage,
+        &mut env,
+        Command::Enqueue {
+            queue: "c".into(),
+            value: Expr::Variable("y".into()),
+            headers: None,
+            dedup: None,
+            group: None,
+            wait: None,
+        }
+    )
+    .is_err());
+
+    Ok(())
+}
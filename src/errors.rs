@@ -4,16 +4,24 @@ use thiserror::Error;
 pub enum SyntaxError {
     #[error("Failed to parse input: {0}")]
     ParseError(String),
+    #[error("Number out of range: {0}")]
+    NumberOutOfRange(String),
 }
 
 #[derive(Error, Debug, Clone, PartialEq, Eq)]
 pub enum DataError {
-    #[error("Failed assertion: {command}\n  expected: {expected}\n  got: {got:?}")]
+    #[error("Failed assertion: {command}\n  expected: {expected}\n  got: {got}")]
     FailedAssertion {
         command: String,
         expected: String,
         got: String,
     },
+    #[error("Undefined variable: ${0}")]
+    UndefinedVariable(String),
+    #[error("Expected {expected}, got {got}")]
+    UnexpectedValue { expected: String, got: String },
+    #[error("Can not compare {0} with {1}")]
+    NotComparable(String, String),
 }
 
 #[derive(Error, Debug, Clone, PartialEq, Eq)]
@@ -22,10 +30,48 @@ pub enum StorageError {
     FailedInitialize,
     #[error("Failed to get lock on the storage")]
     FailedLock,
+    #[error("Queue not found: {0}")]
+    QueueNotFound(String),
+    #[error("Queue is full: {0}")]
+    QueueFull(String),
+    #[error("Unsupported storage format version: {0}")]
+    UnsupportedFormat(u32),
 }
 
 #[derive(Error, Debug, Clone, PartialEq, Eq)]
 pub enum ClientError {
     #[error("Connection error with the server")]
     ConnectionError,
+    #[error("Files are not formatted: {0}")]
+    Unformatted(String),
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum CheckError {
+    #[error("History for queue {0} is not linearizable")]
+    NotLinearizable(String),
+    #[error("Command can not be checked: {0}")]
+    UnsupportedCommand(String),
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ReplicationError {
+    #[error("This node is a read-only replica")]
+    ReadOnly,
+    #[error("This node is not a primary")]
+    NotPrimary,
+    #[error("Primary refused to replicate: {0}")]
+    Refused(String),
+    #[error("{0} is not enabled on this server")]
+    NotEnabled(&'static str),
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum TransactionError {
+    #[error("A transaction is already in progress")]
+    AlreadyStarted,
+    #[error("No transaction in progress")]
+    NotStarted,
+    #[error("Command is not supported inside a transaction")]
+    Unsupported,
 }
@@ -0,0 +1,54 @@
+//! Glob patterns over queue names, as used by `queues`, `scan` and the
+//! configuration templates new queues are created with: `*` matches any run
+//! of characters, possibly empty, and `?` matches exactly one character.
+//! Everything else matches itself.
+
+/// Whether `name` matches the whole of `pattern`.
+pub fn matches(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+
+    let (mut p, mut n) = (0, 0);
+    // Where to resume after the last `*`, should the rest fail to match:
+    // the position after the star, and how much of the name it has taken.
+    let mut backtrack = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p + 1, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, taken)) => {
+                    backtrack = Some((star, taken + 1));
+                    p = star;
+                    n = taken + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[test]
+fn glob_matches_test() {
+    assert!(matches("*", ""));
+    assert!(matches("*", "jobs"));
+    assert!(matches("jobs", "jobs"));
+    assert!(matches("jobs_*", "jobs_email"));
+    assert!(matches("*_email", "jobs_email"));
+    assert!(matches("j?bs*l", "jobs_email"));
+    assert!(matches("*a*a*", "banana"));
+
+    assert!(!matches("jobs", "jobs_email"));
+    assert!(!matches("jobs_*", "jobs"));
+    assert!(!matches("?", ""));
+    assert!(!matches("*a*a*a*a", "banana"));
+}
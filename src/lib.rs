@@ -1,42 +1,522 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
 use std::fmt::Debug;
+use std::time::Duration;
 
 use anyhow::{bail, Result};
 
+pub mod check;
+pub mod environment;
 pub mod errors;
+pub mod glob;
 pub mod parser;
+pub mod replication;
 pub mod storage;
 pub mod types;
 
+use environment::Environment;
 use errors::*;
-use storage::StorageBackend;
+use storage::{Envelope, Headers, Message, QueueConfig, StorageBackend};
 use types::*;
 
+#[cfg(all(test, feature = "memory-storage"))]
+use std::thread;
+
+/// Evaluates an expression to a value, running it if it is a command.
+pub fn evaluate<T: StorageBackend + Send + Sync + Debug>(
+    storage: &T,
+    env: &mut Environment,
+    expr: Expr,
+) -> Result<Value> {
+    match expr {
+        Expr::Value(value) => Ok(value),
+        Expr::Variable(id) => env.get(&id),
+        Expr::Command(cmd) => Ok(run_command(storage, env, *cmd)?.unwrap_or(Value::Null)),
+    }
+}
+
+/// How many queue names `queues` and `scan` read from the storage at a time.
+const SCAN_PAGE_SIZE: usize = 100;
+
+/// Evaluates the glob pattern of `queues` or `scan`, if there is one.
+fn evaluate_pattern<T: StorageBackend + Send + Sync + Debug>(
+    storage: &T,
+    env: &mut Environment,
+    pattern: Option<Expr>,
+) -> Result<Option<String>> {
+    match pattern
+        .map(|pattern| evaluate(storage, env, pattern))
+        .transpose()?
+    {
+        Some(Value::String(pattern)) => Ok(Some(pattern)),
+        Some(pattern) => bail!(DataError::UnexpectedValue {
+            expected: String::from("a string pattern"),
+            got: Literal(&pattern).to_string(),
+        }),
+        None => Ok(None),
+    }
+}
+
+/// Queue names as values, keeping only those that match `pattern`.
+fn queue_names(ids: &[Identifier], pattern: &Option<String>) -> Vec<Value> {
+    ids.iter()
+        .filter(|id| match pattern {
+            Some(pattern) => glob::matches(pattern, &id.0),
+            None => true,
+        })
+        .map(|id| Value::String(id.0.clone()))
+        .collect()
+}
+
+/// Evaluates an expression that has to be a non-negative integer, such as a
+/// position in a queue.
+fn evaluate_count<T: StorageBackend + Send + Sync + Debug>(
+    storage: &T,
+    env: &mut Environment,
+    expr: Expr,
+) -> Result<usize> {
+    match evaluate(storage, env, expr)? {
+        Value::Integer(count) if count >= 0 => Ok(count as usize),
+        count => bail!(DataError::UnexpectedValue {
+            expected: String::from("a non-negative integer"),
+            got: Literal(&count).to_string(),
+        }),
+    }
+}
+
+/// Evaluates the timeout of a command that waits, in seconds.
+fn evaluate_timeout<T: StorageBackend + Send + Sync + Debug>(
+    storage: &T,
+    env: &mut Environment,
+    expr: Expr,
+) -> Result<Duration> {
+    match evaluate(storage, env, expr)? {
+        Value::Integer(seconds) if seconds >= 0 => Ok(Duration::from_secs(seconds as u64)),
+        Value::Float(seconds) if seconds >= 0.0 && seconds.is_finite() => {
+            Ok(Duration::from_secs_f64(seconds))
+        }
+        timeout => bail!(DataError::UnexpectedValue {
+            expected: String::from("a non-negative number of seconds"),
+            got: Literal(&timeout).to_string(),
+        }),
+    }
+}
+
+/// Orders two values of the same type, or an integer and a float.
+fn compare(left: &Value, right: &Value) -> Option<Ordering> {
+    match (left, right) {
+        (Value::Integer(l), Value::Integer(r)) => Some(l.cmp(r)),
+        (Value::Integer(l), Value::Float(r)) => (*l as f64).partial_cmp(r),
+        (Value::Float(l), Value::Integer(r)) => l.partial_cmp(&(*r as f64)),
+        (Value::Float(l), Value::Float(r)) => l.partial_cmp(r),
+        (Value::String(l), Value::String(r)) => Some(l.cmp(r)),
+        (Value::Bytes(l), Value::Bytes(r)) => Some(l.cmp(r)),
+        (Value::Bool(l), Value::Bool(r)) => Some(l.cmp(r)),
+        _ => None,
+    }
+}
+
+/// Evaluates both sides of a condition and compares them. Any two values can
+/// be tested for equality, but only numbers, strings, bytes and booleans can
+/// be ordered.
+pub fn test_condition<T: StorageBackend + Send + Sync + Debug>(
+    storage: &T,
+    env: &mut Environment,
+    condition: &Condition,
+) -> Result<bool> {
+    let left = evaluate(storage, env, condition.left.clone())?;
+    let right = evaluate(storage, env, condition.right.clone())?;
+
+    let ordering = match (compare(&left, &right), condition.comparison) {
+        (None, Comparison::Equal) => return Ok(left == right),
+        (None, Comparison::NotEqual) => return Ok(left != right),
+        (Some(ordering), _) => ordering,
+        (None, _) => bail!(DataError::NotComparable(
+            Literal(&left).to_string(),
+            Literal(&right).to_string()
+        )),
+    };
+
+    Ok(match condition.comparison {
+        Comparison::Equal => ordering == Ordering::Equal,
+        Comparison::NotEqual => ordering != Ordering::Equal,
+        Comparison::Less => ordering == Ordering::Less,
+        Comparison::LessOrEqual => ordering != Ordering::Greater,
+        Comparison::Greater => ordering == Ordering::Greater,
+        Comparison::GreaterOrEqual => ordering != Ordering::Less,
+    })
+}
+
+/// The settings of a queue or template, as `config` replies with them.
+fn config_value(config: &QueueConfig) -> Value {
+    let mut map = BTreeMap::new();
+    map.insert(
+        "max_length".into(),
+        config
+            .max_length
+            .map_or(Value::Null, |max| (max as i64).into()),
+    );
+    map.insert(
+        "overflow".into(),
+        Value::String(config.overflow.to_string()),
+    );
+    map.insert("dedup_window".into(), (config.dedup_window as i64).into());
+    map.insert("mode".into(), Value::String(config.mode.to_string()));
+    map.insert("type".into(), value_type_value(config.value_type));
+
+    map.into()
+}
+
+/// A type as `config` and `info` reply with it, or null for any.
+fn value_type_value(value_type: Option<ValueType>) -> Value {
+    value_type.map_or(Value::Null, |value_type| {
+        Value::String(value_type.to_string())
+    })
+}
+
+/// Evaluates the headers of an enqueue, if there are any.
+fn evaluate_headers<T: StorageBackend + Send + Sync + Debug>(
+    storage: &T,
+    env: &mut Environment,
+    headers: Option<Expr>,
+) -> Result<Headers> {
+    match headers
+        .map(|headers| evaluate(storage, env, headers))
+        .transpose()?
+    {
+        Some(Value::Map(headers)) => Ok(headers),
+        Some(headers) => bail!(DataError::UnexpectedValue {
+            expected: String::from("a map of headers"),
+            got: Literal(&headers).to_string(),
+        }),
+        None => Ok(Headers::new()),
+    }
+}
+
+/// Evaluates a dedup id or group, which must be a string.
+fn evaluate_string<T: StorageBackend + Send + Sync + Debug>(
+    storage: &T,
+    env: &mut Environment,
+    expr: Expr,
+    name: &str,
+) -> Result<String> {
+    match evaluate(storage, env, expr)? {
+        Value::String(string) => Ok(string),
+        value => bail!(DataError::UnexpectedValue {
+            expected: format!("a string {}", name),
+            got: Literal(&value).to_string(),
+        }),
+    }
+}
+
+/// A message as `dequeue` and `peek` reply with it: its value, or the whole
+/// message with `full`. An empty queue replies with null either way.
+fn message_value(message: Option<Message>, reply: Reply) -> Value {
+    let message = match message {
+        Some(message) => message,
+        None => return Value::Null,
+    };
+
+    match reply {
+        Reply::Value => message.value,
+        Reply::Full => {
+            let mut map = BTreeMap::new();
+            map.insert("id".into(), (message.id as i64).into());
+            map.insert("value".into(), message.value);
+            map.insert("headers".into(), Value::Map(message.headers));
+            map.insert("enqueued_at".into(), (message.enqueued_at as i64).into());
+            map.insert("deliveries".into(), (message.deliveries as i64).into());
+            map.insert(
+                "group".into(),
+                message.group.map_or(Value::Null, Value::String),
+            );
+
+            map.into()
+        }
+    }
+}
+
+/// Runs every command of a block in order, stopping at the first error.
+fn run_block<T: StorageBackend + Send + Sync + Debug>(
+    storage: &T,
+    env: &mut Environment,
+    commands: &[Command],
+) -> Result<()> {
+    for command in commands {
+        run_command(storage, env, command.clone())?;
+    }
+
+    Ok(())
+}
+
 #[tracing::instrument]
 pub fn run_command<T: StorageBackend + Send + Sync + Debug>(
     storage: &T,
+    env: &mut Environment,
     command: Command,
 ) -> Result<Option<Value>> {
+    // While a transaction is open, commands are only buffered, to be run
+    // together on commit.
+    if let Some(commands) = env.transaction_mut() {
+        match command {
+            Command::Begin
+            | Command::Commit
+            | Command::Rollback
+            | Command::Comment(_)
+            | Command::Noop => {}
+            // Nothing else can change the storage during a transaction, so
+            // waiting in one would only hold everyone else back. Waits nested
+            // in a block are rejected by the transaction when it commits.
+            Command::Move(_, _, Some(_)) | Command::Enqueue { wait: Some(_), .. } => {
+                bail!(TransactionError::Unsupported)
+            }
+            command => {
+                commands.push(command);
+                return Ok(None);
+            }
+        }
+    }
+
     match command {
-        Command::Enqueue(key, value) => {
-            storage.enqueue(&key, value)?;
-            Ok(None)
+        Command::Enqueue {
+            queue: key,
+            value,
+            headers,
+            dedup,
+            group,
+            wait,
+        } => {
+            let value = evaluate(storage, env, value)?;
+            let envelope = Envelope {
+                headers: evaluate_headers(storage, env, headers)?,
+                dedup_id: dedup
+                    .map(|dedup_id| evaluate_string(storage, env, dedup_id, "dedup id"))
+                    .transpose()?,
+                group: group
+                    .map(|group| evaluate_string(storage, env, group, "group"))
+                    .transpose()?,
+            };
+            let timeout = wait
+                .map(|wait| evaluate_timeout(storage, env, wait))
+                .transpose()?;
+
+            // A duplicate replies with the id of the message it duplicates,
+            // so a retrying producer can't tell it apart from a first try.
+            let enqueued = storage.enqueue_or_wait(&key, value, envelope, timeout)?;
+            Ok(Some((enqueued.id() as i64).into()))
+        }
+        Command::Dequeue(key, reply) => {
+            let message = storage.dequeue(&key)?;
+            Ok(Some(message_value(message, reply)))
         }
-        Command::Dequeue(key) => {
-            let value = storage.dequeue(&key)?;
-            Ok(Some(value))
+        Command::PushFront(key, value, headers) => {
+            // Unlike enqueue, this never waits for room: a full queue that
+            // blocks rejects the value instead.
+            let value = evaluate(storage, env, value)?;
+            let headers = evaluate_headers(storage, env, headers)?;
+            let message = storage.enqueue_front(&key, value, headers)?;
+            Ok(Some((message.id as i64).into()))
+        }
+        Command::PopBack(key, reply) => {
+            let message = storage.pop_back(&key)?;
+            Ok(Some(message_value(message, reply)))
         }
         Command::Length(key) => {
             let value = storage.length(&key)?;
             Ok(Some((value as i64).into()))
         }
-        Command::Peek(key) => {
-            let value = storage.peek(&key)?;
-            Ok(Some(value))
+        Command::Peek(key, reply) => {
+            let message = storage.peek(&key)?;
+            Ok(Some(message_value(message, reply)))
+        }
+        Command::PeekBack(key, reply) => {
+            let message = storage.peek_back(&key)?;
+            Ok(Some(message_value(message, reply)))
+        }
+        Command::Range(key, start, count) => {
+            let start = evaluate_count(storage, env, start)?;
+            let count = evaluate_count(storage, env, count)?;
+
+            let messages = storage.range(&key, start, count)?;
+            let values: Vec<Value> = messages.into_iter().map(|m| m.value).collect();
+
+            Ok(Some(values.into()))
+        }
+        Command::Move(src, dst, None) => {
+            let message = storage.move_value(&src, &dst)?;
+            Ok(Some(message_value(message, Reply::Value)))
+        }
+        Command::Move(src, dst, Some(timeout)) => {
+            let timeout = evaluate_timeout(storage, env, timeout)?;
+            let message = storage.move_or_wait(&src, &dst, timeout)?;
+            Ok(Some(message_value(message, Reply::Value)))
+        }
+        Command::Release(key, group) => {
+            let group = evaluate_string(storage, env, group, "group")?;
+            Ok(Some(storage.release(&key, &group)?.into()))
+        }
+        Command::Purge(key) => {
+            let purged = storage.purge(&key)?;
+            Ok(Some((purged as i64).into()))
+        }
+        Command::Delete(key) => {
+            let existed = storage.delete(&key)?;
+            Ok(Some(existed.into()))
+        }
+        Command::Rename(src, dst) => {
+            storage.rename(&src, &dst)?;
+            Ok(None)
+        }
+        Command::Copy(src, dst) => {
+            storage.copy(&src, &dst)?;
+            Ok(None)
+        }
+        Command::Queues(pattern) => {
+            let pattern = evaluate_pattern(storage, env, pattern)?;
+            let mut names = vec![];
+            let mut after = None;
+
+            loop {
+                let page = storage.scan(after.as_ref(), SCAN_PAGE_SIZE)?;
+                names.extend(queue_names(&page, &pattern));
+
+                if page.len() < SCAN_PAGE_SIZE {
+                    return Ok(Some(names.into()));
+                }
+
+                after = page.last().cloned();
+            }
+        }
+        Command::Scan(cursor, pattern) => {
+            let after = match evaluate(storage, env, cursor)? {
+                Value::Null => None,
+                Value::String(after) => Some(Identifier(after)),
+                cursor => bail!(DataError::UnexpectedValue {
+                    expected: String::from("a string or null cursor"),
+                    got: Literal(&cursor).to_string(),
+                }),
+            };
+            let pattern = evaluate_pattern(storage, env, pattern)?;
+
+            let page = storage.scan(after.as_ref(), SCAN_PAGE_SIZE)?;
+            let next = match page.last() {
+                Some(last) if page.len() == SCAN_PAGE_SIZE => Value::String(last.0.clone()),
+                _ => Value::Null,
+            };
+
+            Ok(Some(vec![next, queue_names(&page, &pattern).into()].into()))
+        }
+        Command::Info(key) => {
+            let info = match storage.info(&key)? {
+                Some(info) => info,
+                None => return Ok(Some(Value::Null)),
+            };
+
+            let mut map = BTreeMap::new();
+            map.insert("mode".into(), Value::String(info.mode.to_string()));
+            map.insert("type".into(), value_type_value(info.value_type));
+            map.insert("length".into(), (info.length as i64).into());
+            map.insert("created_at".into(), (info.created_at as i64).into());
+            map.insert("enqueued".into(), (info.enqueued as i64).into());
+            map.insert("dequeued".into(), (info.dequeued as i64).into());
+            map.insert(
+                "oldest_age".into(),
+                info.oldest_age
+                    .map_or(Value::Null, |age| (age as i64).into()),
+            );
+
+            Ok(Some(map.into()))
+        }
+        Command::Open(key, value_type, mode) => {
+            storage.update_config(&key, &mut |config| {
+                if value_type.is_some() {
+                    config.value_type = value_type;
+                }
+
+                if let Some(mode) = mode {
+                    config.mode = mode;
+                }
+            })?;
+
+            Ok(None)
+        }
+        Command::Configure(target, settings) => {
+            // Every setting is evaluated before anything is changed, so a bad
+            // one leaves the queue or template as it was.
+            let mut max_length = None;
+            let mut overflow = None;
+            let mut dedup_window = None;
+            let mut mode = None;
+            let mut value_type = None;
+
+            for setting in settings {
+                match setting {
+                    Setting::MaxLength(expr) => {
+                        // A queue that can't hold anything would drop or
+                        // reject every value enqueued into it.
+                        max_length = Some(match evaluate(storage, env, expr)? {
+                            Value::Null => None,
+                            Value::Integer(0) => bail!(DataError::UnexpectedValue {
+                                expected: String::from("a positive max length or null"),
+                                got: String::from("0"),
+                            }),
+                            value => Some(evaluate_count(storage, env, Expr::Value(value))?),
+                        })
+                    }
+                    Setting::Overflow(policy) => overflow = Some(policy),
+                    Setting::DedupWindow(expr) => {
+                        dedup_window = Some(evaluate_count(storage, env, expr)? as u64)
+                    }
+                    Setting::Mode(value) => mode = Some(value),
+                    Setting::Type(value) => value_type = Some(value),
+                }
+            }
+
+            let mut update = |config: &mut QueueConfig| {
+                if let Some(max_length) = max_length {
+                    config.max_length = max_length;
+                }
+
+                if let Some(overflow) = overflow {
+                    config.overflow = overflow;
+                }
+
+                if let Some(dedup_window) = dedup_window {
+                    config.dedup_window = dedup_window;
+                }
+
+                if let Some(mode) = mode {
+                    config.mode = mode;
+                }
+
+                if let Some(value_type) = value_type {
+                    config.value_type = value_type;
+                }
+            };
+
+            match target {
+                ConfigTarget::Queue(key) => storage.update_config(&key, &mut update)?,
+                ConfigTarget::Template(pattern) => {
+                    storage.update_template(&pattern, &mut update)?
+                }
+            }
+
+            Ok(None)
         }
+        Command::Config(ConfigTarget::Queue(key)) => Ok(Some(config_value(&storage.config(&key)?))),
+        Command::Config(ConfigTarget::Template(pattern)) => Ok(Some(
+            storage
+                .templates()?
+                .get(&pattern)
+                .map_or(Value::Null, config_value),
+        )),
         Command::Assert(cmd, val) => {
-            let cmd_desc = format!("{:?}", &cmd);
+            let cmd_desc = cmd.to_string();
+
+            let result = run_command(storage, env, *cmd)?;
+            let val = evaluate(storage, env, val)?;
 
-            match run_command(storage, *cmd)? {
+            match result {
                 Some(result) => {
                     if result == val {
                         return Ok(None);
@@ -44,36 +524,486 @@ pub fn run_command<T: StorageBackend + Send + Sync + Debug>(
 
                     bail!(DataError::FailedAssertion {
                         command: cmd_desc,
-                        expected: format!("{:?}", val),
-                        got: format!("{:?}", result),
+                        expected: Literal(&val).to_string(),
+                        got: Literal(&result).to_string(),
                     })
                 }
                 None => {
                     bail!(DataError::FailedAssertion {
                         command: cmd_desc,
-                        expected: format!("{:?}", val),
-                        got: format!("{:?}", Value::Null)
+                        expected: Literal(&val).to_string(),
+                        got: Literal(&Value::Null).to_string()
                     })
                 }
             }
         }
         Command::AssertError(cmd) => {
-            let cmd_desc = format!("{:?}", &cmd);
+            let cmd_desc = cmd.to_string();
 
-            match run_command(storage, *cmd) {
+            match run_command(storage, env, *cmd) {
                 Ok(Some(result)) => bail!(DataError::FailedAssertion {
                     command: cmd_desc,
                     expected: String::from("Error"),
-                    got: format!("{:?}", result),
+                    got: Literal(&result).to_string(),
                 }),
                 Ok(None) => bail!(DataError::FailedAssertion {
                     command: cmd_desc,
                     expected: String::from("Error"),
-                    got: format!("{:?}", Value::Null)
+                    got: Literal(&Value::Null).to_string()
                 }),
                 Err(_) => Ok(None),
             }
         }
-        Command::Noop => Ok(None),
+        Command::Let(id, value) => {
+            let value = evaluate(storage, env, value)?;
+            env.set(id, value);
+            Ok(None)
+        }
+        Command::Repeat(count, body) => {
+            let count = evaluate_count(storage, env, count)?;
+
+            for _ in 0..count {
+                run_block(storage, env, &body)?;
+            }
+
+            Ok(None)
+        }
+        Command::While(condition, body) => {
+            while test_condition(storage, env, &condition)? {
+                run_block(storage, env, &body)?;
+            }
+
+            Ok(None)
+        }
+        Command::If(condition, then, otherwise) => {
+            if test_condition(storage, env, &condition)? {
+                run_block(storage, env, &then)?;
+            } else {
+                run_block(storage, env, &otherwise)?;
+            }
+
+            Ok(None)
+        }
+        Command::Begin => {
+            env.begin()?;
+            Ok(None)
+        }
+        Command::Commit => {
+            let commands = env.end()?;
+            let mut committed = env.clone();
+            let mut results = vec![];
+
+            storage.transaction(&mut |tx| {
+                let mut scratch = env.clone();
+
+                results = commands
+                    .iter()
+                    .map(|command| {
+                        let result = run_command(tx, &mut scratch, command.clone())?;
+                        Ok(result.unwrap_or(Value::Null))
+                    })
+                    .collect::<Result<_>>()?;
+
+                // A `begin` nested in a block would leave a transaction open
+                // that the caller never started, and buffer everything after.
+                if scratch.transaction_mut().is_some() {
+                    bail!(TransactionError::Unsupported);
+                }
+
+                committed = scratch;
+                Ok(())
+            })?;
+
+            // Variables bound inside the transaction only stay if it commits.
+            *env = committed;
+
+            Ok(Some(Value::List(results)))
+        }
+        Command::Rollback => {
+            env.end()?;
+            Ok(None)
+        }
+        Command::Comment(_) | Command::Noop => Ok(None),
+    }
+}
+
+/// Runs a whole program, returning the result of its last command.
+#[cfg(test)]
+fn run_source<T: StorageBackend + Send + Sync + Debug>(
+    storage: &T,
+    env: &mut Environment,
+    source: &str,
+) -> Result<Option<Value>> {
+    let mut result = None;
+
+    for command in parser::parse(source)? {
+        result = run_command(storage, env, command)?;
+    }
+
+    Ok(result)
+}
+
+/// Runs programs one after another against `storage`, sharing their
+/// variables and transactions like a client connection does.
+#[cfg(test)]
+fn session_with<T: StorageBackend + Send + Sync + Debug>(
+    storage: T,
+) -> impl FnMut(&str) -> Result<Option<Value>> {
+    let mut env = Environment::new();
+    move |source| run_source(&storage, &mut env, source)
+}
+
+/// A session against a new, empty storage.
+#[cfg(all(test, feature = "memory-storage"))]
+fn session() -> impl FnMut(&str) -> Result<Option<Value>> {
+    session_with(storage::Storage::new())
+}
+
+/// The entries of a map, such as the result of `info` or `config`.
+#[cfg(test)]
+fn entries(value: Option<Value>) -> BTreeMap<String, Value> {
+    match value {
+        Some(Value::Map(entries)) => entries,
+        value => panic!("Unexpected value {:?}", value),
     }
 }
+
+#[cfg(all(test, feature = "memory-storage"))]
+#[test]
+fn blocks_run_their_commands() -> Result<()> {
+    let mut run = session();
+
+    run("repeat 5 { enqueue a 1 }\n\
+        while (length a) > 2 { dequeue a }\n\
+        if (length a) == 2.0 { enqueue b \"two\" } else { enqueue b \"other\" }")?;
+    assert_eq!(run("length a")?, Some(2.into()));
+    assert_eq!(run("peek b")?, Some(Value::String("two".into())));
+
+    for invalid in ["repeat -1 {}", "repeat \"a\" {}", "if [1] < [2] {}"] {
+        assert!(run(invalid).is_err());
+    }
+
+    Ok(())
+}
+
+#[cfg(all(test, feature = "memory-storage"))]
+#[test]
+fn transactions_apply_all_or_nothing() -> Result<()> {
+    let mut run = session();
+
+    run("enqueue a 1\nenqueue a 2")?;
+
+    let committed = run("begin\nlet x = (dequeue a)\nenqueue b $x\nlength b\ncommit")?;
+    assert_eq!(
+        committed,
+        Some(vec![Value::Null, 3.into(), 1.into()].into())
+    );
+    assert_eq!(run("length a")?, Some(1.into()));
+    assert_eq!(run("peek b")?, Some(1.into()));
+
+    assert!(run("begin\ndequeue a\nenqueue b $undefined\ncommit").is_err());
+    assert_eq!(run("length a")?, Some(1.into()));
+
+    run("begin\ndequeue a\nrollback")?;
+    assert_eq!(run("length a")?, Some(1.into()));
+
+    // A nested begin fails the whole transaction, and leaves none open.
+    assert!(run("begin\ndequeue a\nrepeat 1 { begin }\ncommit").is_err());
+    run("enqueue q 1")?;
+    assert_eq!(run("length q")?, Some(1.into()));
+    assert_eq!(run("length a")?, Some(1.into()));
+
+    assert!(run("commit").is_err());
+    assert!(run("begin\nbegin").is_err());
+
+    Ok(())
+}
+
+#[cfg(all(test, feature = "memory-storage"))]
+#[test]
+fn move_transfers_values_between_queues() -> Result<()> {
+    let mut run = session();
+
+    run("enqueue a null\nenqueue a 2")?;
+
+    assert_eq!(run("move a b")?, Some(Value::Null));
+    assert_eq!(run("move a b wait 1")?, Some(2.into()));
+    assert_eq!(run("move a b wait 0.01")?, Some(Value::Null));
+    assert_eq!(run("length b")?, Some(2.into()));
+
+    assert_eq!(
+        run("begin\nmove b a\ncommit")?,
+        Some(vec![Value::Null].into())
+    );
+    assert_eq!(run("length a")?, Some(1.into()));
+    assert!(run("move a b wait -1").is_err());
+
+    // A wait nested in a block can't hold the storage for its timeout.
+    assert!(run("begin\nrepeat 1 { move c b wait 2 }\ncommit").is_err());
+    assert!(run("begin\nlet x = (move c b wait 2)\ncommit").is_err());
+
+    run("enqueue a 3\nenqueue a 4")?;
+    assert_eq!(
+        run("range a 1 5")?,
+        Some(Value::List(vec![3.into(), 4.into()]))
+    );
+    assert_eq!(run("peek a 1")?, Some(Value::List(vec![Value::Null])));
+    assert_eq!(run("range a 10 1")?, Some(Value::List(vec![])));
+    assert!(run("range a -1 1").is_err());
+
+    Ok(())
+}
+
+#[cfg(all(test, feature = "memory-storage"))]
+#[test]
+fn blocking_move_waits_for_a_value() -> Result<()> {
+    let storage = storage::Storage::new();
+    let producer = storage.clone();
+
+    let handle = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        producer.enqueue(&"a".into(), 1.into())
+    });
+
+    let moved = storage.move_or_wait(&"a".into(), &"b".into(), Duration::from_secs(5))?;
+    handle.join().unwrap()?;
+
+    assert_eq!(moved.map(|m| m.value), Some(1.into()));
+    assert_eq!(storage.peek(&"b".into())?.map(|m| m.value), Some(1.into()));
+
+    Ok(())
+}
+
+#[cfg(all(test, feature = "memory-storage"))]
+#[test]
+fn queues_are_listed_and_described() -> Result<()> {
+    let mut run = session();
+
+    run("enqueue jobs_email 1\nenqueue jobs_sms 1\nenqueue other 1\ndequeue other")?;
+
+    let all = vec!["jobs_email", "jobs_sms", "other"];
+    let all = all
+        .into_iter()
+        .map(|q| Value::String(q.into()))
+        .collect::<Vec<_>>();
+
+    assert_eq!(run("queues")?, Some(all.clone().into()));
+    assert_eq!(run("queues \"*_sms\"")?, Some(vec![all[1].clone()].into()));
+    assert_eq!(
+        run("scan null \"jobs_*\"")?,
+        Some(vec![Value::Null, all[..2].to_vec().into()].into())
+    );
+    assert!(run("queues 1").is_err());
+
+    let info = entries(run("info other")?);
+    assert_eq!(info["length"], 0.into());
+    assert_eq!(info["enqueued"], 1.into());
+    assert_eq!(info["dequeued"], 1.into());
+    assert_eq!(info["oldest_age"], Value::Null);
+    assert_eq!(run("info missing")?, Some(Value::Null));
+
+    Ok(())
+}
+
+#[cfg(all(test, feature = "memory-storage"))]
+#[test]
+fn full_queues_follow_their_overflow_policy() -> Result<()> {
+    let storage = storage::Storage::new();
+    let mut run = session_with(storage.clone());
+
+    run("configure a max_length 1\nenqueue a 1\nconfigure a overflow block")?;
+    assert!(run("configure a max_length -1").is_err());
+    assert!(run("configure b max_length 0").is_err());
+    assert!(run("begin\nenqueue a 2\ncommit").is_err());
+    assert!(run("begin\nrepeat 1 { enqueue a 2 wait 1 }\ncommit").is_err());
+
+    let error = run("enqueue a 2 wait 0.05").unwrap_err();
+    assert!(matches!(
+        error.downcast_ref(),
+        Some(StorageError::QueueFull(_))
+    ));
+
+    let handle = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        storage.dequeue(&"a".into())
+    });
+
+    run("enqueue a 2")?;
+    assert_eq!(handle.join().unwrap()?.map(|m| m.value), Some(1.into()));
+    assert_eq!(run("peek a")?, Some(2.into()));
+
+    run("configure a max_length null\nenqueue a 3")?;
+    assert_eq!(run("length a")?, Some(2.into()));
+
+    Ok(())
+}
+
+#[cfg(all(test, feature = "memory-storage"))]
+#[test]
+fn queues_are_configured_from_templates() -> Result<()> {
+    let mut run = session();
+
+    assert_eq!(run("config \"orders_*\"")?, Some(Value::Null));
+    run("configure \"orders_*\" max_length 1 overflow drop_oldest")?;
+    run("enqueue orders_eu 1\nenqueue orders_eu 2")?;
+    assert_eq!(run("length orders_eu")?, Some(1.into()));
+
+    run("configure orders_eu overflow reject")?;
+    let config = entries(run("config orders_eu")?);
+    assert_eq!(config["max_length"], 1.into());
+    assert_eq!(config["overflow"], Value::String("reject".into()));
+
+    assert_eq!(run("config \"orders_*\"")?, run("config orders_us")?);
+    assert!(run("begin\nconfigure \"jobs_*\" max_length 1\ncommit").is_err());
+
+    Ok(())
+}
+
+#[cfg(all(test, feature = "memory-storage"))]
+#[test]
+fn messages_carry_ids_and_headers() -> Result<()> {
+    let mut run = session();
+
+    assert_eq!(run("enqueue a 1")?, Some(1.into()));
+    assert_eq!(run("enqueue a 2 headers {trace: \"t1\"}")?, Some(2.into()));
+    assert!(run("enqueue a 3 headers [1]").is_err());
+    assert_eq!(run("dequeue a")?, Some(1.into()));
+
+    let message = entries(run("peek a full")?);
+    assert_eq!(message["id"], 2.into());
+    assert_eq!(message["value"], 2.into());
+    assert_eq!(message["deliveries"], 0.into());
+
+    let mut headers = BTreeMap::new();
+    headers.insert("trace".into(), Value::String("t1".into()));
+    assert_eq!(message["headers"], headers.into());
+
+    run("move a b")?;
+    let message = entries(run("dequeue b full")?);
+    assert_eq!(message["id"], 2.into());
+    assert_eq!(message["deliveries"], 2.into());
+    assert_eq!(run("dequeue b full")?, Some(Value::Null));
+
+    Ok(())
+}
+
+#[cfg(all(test, feature = "memory-storage"))]
+#[test]
+fn retried_enqueues_are_deduplicated() -> Result<()> {
+    let mut run = session();
+
+    assert_eq!(run("enqueue a 1 dedup \"order-1\"")?, Some(1.into()));
+    assert_eq!(
+        run("enqueue a 1 headers {retry: 1} dedup \"order-1\"")?,
+        Some(1.into())
+    );
+    assert_eq!(
+        run("begin\nenqueue a 2 dedup \"order-1\"\nenqueue a 2 dedup \"order-2\"\ncommit")?,
+        Some(vec![Value::from(1), 2.into()].into())
+    );
+    assert!(run("enqueue a 3 dedup 3").is_err());
+    assert_eq!(run("length a")?, Some(2.into()));
+
+    run("configure a dedup_window 0")?;
+    assert_eq!(run("enqueue a 1 dedup \"order-1\"")?, Some(3.into()));
+    assert!(run("configure a dedup_window -1").is_err());
+
+    Ok(())
+}
+
+#[cfg(all(test, feature = "memory-storage"))]
+#[test]
+fn groups_are_released_by_their_consumer() -> Result<()> {
+    let mut run = session();
+
+    run("enqueue a 1 group c1\nenqueue a 2 group c1\nenqueue a 3 group \"c2\"")?;
+    assert!(run("let g = 4\nenqueue a 4 group $g").is_err());
+
+    let message = entries(run("dequeue a full")?);
+    assert_eq!(message["group"], Value::String("c1".into()));
+
+    assert_eq!(run("dequeue a")?, Some(3.into()));
+    assert_eq!(run("dequeue a")?, Some(Value::Null));
+    assert_eq!(
+        run("begin\nrelease a c1\ndequeue a\ncommit")?,
+        Some(vec![Value::from(true), 2.into()].into())
+    );
+    assert_eq!(run("release a c3")?, Some(false.into()));
+
+    Ok(())
+}
+
+#[cfg(all(test, feature = "memory-storage"))]
+#[test]
+fn stacks_and_deques_are_used_from_either_end() -> Result<()> {
+    let mut run = session();
+
+    run("enqueue a 2\npush_front a 1\nenqueue a 3")?;
+    assert_eq!(run("peek_back a")?, Some(3.into()));
+    assert_eq!(run("pop_back a")?, Some(3.into()));
+    assert_eq!(run("dequeue a")?, Some(1.into()));
+    assert_eq!(run("pop_back b")?, Some(Value::Null));
+
+    run("open s mode stack\nenqueue s 1\nenqueue s 2")?;
+    assert_eq!(run("dequeue s")?, Some(2.into()));
+    assert_eq!(
+        entries(run("info s")?)["mode"],
+        Value::String("stack".into())
+    );
+
+    Ok(())
+}
+
+#[cfg(all(test, feature = "memory-storage"))]
+#[test]
+fn opened_queues_only_accept_their_type() -> Result<()> {
+    let mut run = session();
+
+    run("open a :integer")?;
+    let info = entries(run("info a")?);
+    assert_eq!(info["type"], Value::String(":integer".into()));
+    assert_eq!(info["length"], 0.into());
+    run("enqueue a 1")?;
+    assert!(run("enqueue a 1.5").is_err());
+    assert!(run("push_front a \"1\"").is_err());
+    assert!(run("enqueue b 1.5\nmove b a").is_err());
+    assert_eq!(run("length b")?, Some(1.into()));
+
+    // Opening it again keeps its type unless another is given.
+    run("open a mode stack")?;
+    assert!(run("enqueue a null").is_err());
+    run("configure a type any\nenqueue a null")?;
+    assert_eq!(run("length a")?, Some(2.into()));
+    assert_eq!(entries(run("config a")?)["type"], Value::Null);
+
+    Ok(())
+}
+
+#[cfg(all(test, feature = "rocksdb-storage"))]
+#[test]
+fn queues_keep_their_messages_and_config_when_reopened() -> Result<()> {
+    let path = std::env::temp_dir().join(format!("xq-session-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+    let path = path.to_string_lossy().into_owned();
+
+    let mut run = session_with(storage::Storage::init(&path)?);
+    run("open a :integer mode stack\nenqueue a 1 dedup \"x\"\nenqueue a 2")?;
+    assert_eq!(run("enqueue a 1 dedup \"x\"")?, Some(1.into()));
+    assert!(run("enqueue a \"3\"").is_err());
+    assert_eq!(
+        run("begin\ndequeue a\nmove a b\ncommit")?,
+        Some(vec![Value::from(2), 1.into()].into())
+    );
+    drop(run);
+
+    let mut run = session_with(storage::Storage::init(&path)?);
+    assert_eq!(run("length a")?, Some(0.into()));
+    assert_eq!(run("peek b")?, Some(1.into()));
+    let config = entries(run("config a")?);
+    assert_eq!(config["type"], Value::String(":integer".into()));
+    assert_eq!(config["mode"], Value::String("stack".into()));
+    drop(run);
+
+    std::fs::remove_dir_all(&path)?;
+    Ok(())
+}
@@ -0,0 +1,77 @@
+//! Byte strings follow the same structure as the string parser in
+//! `string.rs`, but work on bytes: literal text is taken as its UTF-8 bytes,
+//! and `\xNN` escapes allow any byte that isn't valid text.
+
+use nom::branch::alt;
+use nom::bytes::complete::{is_not, tag, take_while_m_n};
+use nom::character::complete::char;
+use nom::combinator::{map, map_res, value, verify};
+use nom::multi::fold_many0;
+use nom::sequence::{delimited, preceded};
+use nom::IResult;
+
+/// Parse a byte given as exactly two hexadecimal numerals, as in \x7f.
+fn parse_hex_byte(input: &str) -> IResult<&str, u8> {
+    let parse_hex = take_while_m_n(2, 2, |c: char| c.is_ascii_hexdigit());
+
+    map_res(preceded(char('x'), parse_hex), |hex| {
+        u8::from_str_radix(hex, 16)
+    })(input)
+}
+
+/// Parse an escaped byte: \n, \t, \r, \0, \x00, etc.
+fn parse_escaped_byte(input: &str) -> IResult<&str, u8> {
+    preceded(
+        char('\\'),
+        alt((
+            parse_hex_byte,
+            value(b'\n', char('n')),
+            value(b'\r', char('r')),
+            value(b'\t', char('t')),
+            value(b'\0', char('0')),
+            value(b'\\', char('\\')),
+            value(b'"', char('"')),
+        )),
+    )(input)
+}
+
+/// Parse a non-empty block of text that doesn't include \ or "
+fn parse_literal(input: &str) -> IResult<&str, &str> {
+    verify(is_not("\"\\"), |s: &str| !s.is_empty())(input)
+}
+
+enum BytesFragment<'a> {
+    Literal(&'a str),
+    EscapedByte(u8),
+}
+
+fn parse_fragment(input: &str) -> IResult<&str, BytesFragment<'_>> {
+    alt((
+        map(parse_literal, BytesFragment::Literal),
+        map(parse_escaped_byte, BytesFragment::EscapedByte),
+    ))(input)
+}
+
+/// Parse a byte string literal, of the form b"...".
+pub fn parse_bytes(input: &str) -> IResult<&str, Vec<u8>> {
+    let build_bytes = fold_many0(parse_fragment, Vec::new, |mut bytes, fragment| {
+        match fragment {
+            BytesFragment::Literal(s) => bytes.extend_from_slice(s.as_bytes()),
+            BytesFragment::EscapedByte(b) => bytes.push(b),
+        }
+        bytes
+    });
+
+    delimited(tag("b\""), build_bytes, char('"'))(input)
+}
+
+#[test]
+fn parse_bytes_test() {
+    assert_eq!(parse_bytes("b\"abc\""), Ok(("", b"abc".to_vec())));
+    assert_eq!(
+        parse_bytes("b\"\\x00\\xff\\n\\\"\""),
+        Ok(("", vec![0, 255, b'\n', b'"']))
+    );
+    assert!(parse_bytes("b\"\\xf\"").is_err());
+    assert!(parse_bytes("\"abc\"").is_err());
+}
@@ -4,31 +4,103 @@ use nom::{
     bytes::complete::*,
     character::complete::*,
     combinator::*,
-    multi::{many0, many1},
-    sequence::{delimited, pair, terminated, tuple},
+    multi::{many0, many1, separated_list0},
+    sequence::{delimited, pair, preceded, separated_pair, terminated, tuple},
     IResult,
 };
 
+mod bytes;
 mod string;
 
 use crate::errors::*;
-use crate::types::{Command, Identifier, Value};
+use crate::types::{
+    Command, Comparison, Condition, ConfigTarget, Expr, Identifier, Mode, Overflow, Reply, Setting,
+    Value, ValueType,
+};
+
+/// Digits in the given radix, starting with a digit and optionally separated
+/// by underscores, as in `1_000_000`.
+fn digits(radix: u32) -> impl Fn(&str) -> IResult<&str, &str> {
+    move |input| {
+        recognize(pair(
+            satisfy(move |c| c.is_digit(radix)),
+            take_while(move |c: char| c.is_digit(radix) || c == '_'),
+        ))(input)
+    }
+}
+
+fn sign(input: &str) -> IResult<&str, Option<char>> {
+    opt(one_of("+-"))(input)
+}
 
-fn int_to_value(input: &str) -> Result<Value> {
-    Ok(i64::from_str_radix(input, 10)?.into())
+/// Fails the whole parse, instead of letting another alternative (say, a
+/// float) silently take over a number that doesn't fit its type.
+fn out_of_range<T>(input: &str) -> IResult<&str, T> {
+    Err(nom::Err::Failure(nom::error::Error::new(
+        input,
+        nom::error::ErrorKind::TooLarge,
+    )))
+}
+
+fn integer_in_radix<'a>(
+    input: &'a str,
+    sign: Option<char>,
+    radix: u32,
+    digits: &str,
+    rest: &'a str,
+) -> IResult<&'a str, Value> {
+    let text = format!("{}{}", sign.unwrap_or('+'), digits.replace('_', ""));
+
+    match i64::from_str_radix(&text, radix) {
+        Ok(v) => Ok((rest, v.into())),
+        Err(_) => out_of_range(input),
+    }
 }
 
 fn decimal(input: &str) -> IResult<&str, Value> {
-    map_res(
-        recognize(many1(terminated(one_of("0123456789"), many0(char('_'))))),
-        int_to_value,
-    )(input)
+    let (rest, (sign, digits)) = pair(sign, digits(10))(input)?;
+
+    // A fraction or an exponent makes this a float.
+    if rest.starts_with(['.', 'e', 'E']) {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::Digit,
+        )));
+    }
+
+    integer_in_radix(input, sign, 10, digits, rest)
+}
+
+fn radix_integer(input: &str) -> IResult<&str, Value> {
+    let (rest, (sign, (radix, digits))) = pair(
+        sign,
+        alt((
+            pair(value(16, tag_no_case("0x")), digits(16)),
+            pair(value(8, tag_no_case("0o")), digits(8)),
+            pair(value(2, tag_no_case("0b")), digits(2)),
+        )),
+    )(input)?;
+
+    integer_in_radix(input, sign, radix, digits, rest)
+}
+
+fn integer(input: &str) -> IResult<&str, Value> {
+    alt((radix_integer, decimal))(input)
 }
 
 fn float(input: &str) -> IResult<&str, Value> {
-    map_res(nom::number::complete::float, |out: f32| -> Result<Value> {
-        Ok(out.into())
-    })(input)
+    let exponent = || recognize(tuple((one_of("eE"), sign, digits(10))));
+    let fraction = recognize(pair(pair(char('.'), digits(10)), opt(exponent())));
+
+    let (rest, text) = alt((
+        recognize(pair(sign, tag("inf"))),
+        recognize(tuple((sign, digits(10), alt((fraction, exponent()))))),
+    ))(input)?;
+
+    match text.replace('_', "").parse::<f64>() {
+        Ok(v) if v.is_finite() || text.ends_with("inf") => Ok((rest, v.into())),
+        _ => out_of_range(input),
+    }
 }
 
 fn string(input: &str) -> IResult<&str, Value> {
@@ -41,6 +113,51 @@ fn null(input: &str) -> IResult<&str, Value> {
     value(Value::Null, tag("null"))(input)
 }
 
+fn boolean(input: &str) -> IResult<&str, Value> {
+    alt((
+        value(Value::Bool(true), tag("true")),
+        value(Value::Bool(false), tag("false")),
+    ))(input)
+}
+
+fn byte_string(input: &str) -> IResult<&str, Value> {
+    map(bytes::parse_bytes, Value::Bytes)(input)
+}
+
+/// Separator between the elements of a list or a map: a comma, optionally
+/// surrounded by whitespace.
+fn separator(input: &str) -> IResult<&str, char> {
+    delimited(multispace0, char(','), multispace0)(input)
+}
+
+fn list(input: &str) -> IResult<&str, Value> {
+    map(
+        delimited(
+            pair(char('['), multispace0),
+            separated_list0(separator, val),
+            pair(multispace0, char(']')),
+        ),
+        Value::List,
+    )(input)
+}
+
+fn map_key(input: &str) -> IResult<&str, String> {
+    alt((string::parse_string, map(identifier, |Identifier(key)| key)))(input)
+}
+
+fn map_value(input: &str) -> IResult<&str, Value> {
+    let entry = separated_pair(map_key, delimited(multispace0, char(':'), multispace0), val);
+
+    map(
+        delimited(
+            pair(char('{'), multispace0),
+            separated_list0(separator, entry),
+            pair(multispace0, char('}')),
+        ),
+        |entries| Value::Map(entries.into_iter().collect()),
+    )(input)
+}
+
 fn identifier(input: &str) -> IResult<&str, Identifier> {
     map_res(
         recognize(pair(
@@ -52,22 +169,126 @@ fn identifier(input: &str) -> IResult<&str, Identifier> {
 }
 
 fn val(input: &str) -> IResult<&str, Value> {
-    alt((decimal, float, string, null))(input)
+    alt((
+        integer,
+        float,
+        string,
+        null,
+        boolean,
+        byte_string,
+        list,
+        map_value,
+    ))(input)
+}
+
+fn variable(input: &str) -> IResult<&str, Identifier> {
+    preceded(char('$'), identifier)(input)
+}
+
+/// Anything in a value position: a literal, a `$variable`, or a command in
+/// parentheses, which evaluates to its result.
+fn operand(input: &str) -> IResult<&str, Expr> {
+    alt((
+        map(val, Expr::Value),
+        map(variable, Expr::Variable),
+        map(delimited(char('('), expr, char(')')), Expr::from),
+    ))(input)
+}
+
+/// A group name: a bare word, as in `group customer42`, a string, a
+/// `$variable` or a command in parentheses. Literals that can't be a group,
+/// such as `true` or `5`, are rejected here rather than when the command
+/// runs.
+fn group_name(input: &str) -> IResult<&str, Expr> {
+    let word = verify(terminated(identifier, not(char('"'))), |id: &Identifier| {
+        !matches!(id.0.as_str(), "true" | "false" | "null" | "inf")
+    });
+
+    alt((
+        map(word, |id| Expr::Value(Value::String(id.0))),
+        map(string, Expr::Value),
+        map(variable, Expr::Variable),
+        map(delimited(char('('), expr, char(')')), Expr::from),
+    ))(input)
 }
 
 fn enqueue(input: &str) -> IResult<&str, Command> {
-    map_res(
-        tuple((tag("enqueue"), multispace1, identifier, multispace1, val)),
-        |(_, _, id, _, val): (&str, &str, Identifier, &str, Value)| -> Result<Command> {
-            Ok(Command::enqueue(id, val))
+    let headers = preceded(tuple((multispace1, tag("headers"), multispace1)), operand);
+    let dedup = preceded(tuple((multispace1, tag("dedup"), multispace1)), operand);
+    let group = preceded(tuple((multispace1, tag("group"), multispace1)), group_name);
+    let timeout = preceded(tuple((multispace1, tag("wait"), multispace1)), operand);
+
+    map(
+        tuple((
+            tag("enqueue"),
+            multispace1,
+            identifier,
+            multispace1,
+            operand,
+            opt(headers),
+            opt(dedup),
+            opt(group),
+            opt(timeout),
+        )),
+        |(_, _, queue, _, value, headers, dedup, group, wait)| Command::Enqueue {
+            queue,
+            value,
+            headers,
+            dedup,
+            group,
+            wait,
         },
     )(input)
 }
 
+fn release(input: &str) -> IResult<&str, Command> {
+    map(
+        tuple((
+            tag("release"),
+            multispace1,
+            identifier,
+            multispace1,
+            group_name,
+        )),
+        |(_, _, id, _, group)| Command::Release(id, group),
+    )(input)
+}
+
+/// A trailing `full`, asking for the whole message rather than its value.
+fn reply(input: &str) -> IResult<&str, Reply> {
+    map(opt(preceded(space1, tag("full"))), |full| match full {
+        Some(_) => Reply::Full,
+        None => Reply::Value,
+    })(input)
+}
+
 fn dequeue(input: &str) -> IResult<&str, Command> {
-    map_res(
-        tuple((tag("dequeue"), multispace1, identifier)),
-        |(_, _, id): (&str, &str, Identifier)| -> Result<Command> { Ok(Command::Dequeue(id)) },
+    map(
+        tuple((tag("dequeue"), multispace1, identifier, reply)),
+        |(_, _, id, reply)| Command::Dequeue(id, reply),
+    )(input)
+}
+
+fn push_front(input: &str) -> IResult<&str, Command> {
+    let headers = preceded(tuple((multispace1, tag("headers"), multispace1)), operand);
+
+    map(
+        tuple((
+            tag("push_front"),
+            multispace1,
+            identifier,
+            multispace1,
+            operand,
+            opt(headers),
+        )),
+        |(_, _, id, _, val, headers)| Command::PushFront(id, val, headers),
+    )(input)
+}
+
+fn pop_back(input: &str) -> IResult<&str, Command> {
+    map(
+        tuple((tag("pop_back"), multispace1, identifier, reply)),
+        |(_, _, id, reply)| Command::PopBack(id, reply),
     )(input)
 }
 
@@ -78,20 +299,238 @@ fn length(input: &str) -> IResult<&str, Command> {
     )(input)
 }
 
+/// `peek key` or `peek key full`, or `peek key n`, which is the same as
+/// `range key 0 n` and so can't be `full`.
 fn peek(input: &str) -> IResult<&str, Command> {
-    map_res(
-        tuple((tag("peek"), multispace1, identifier)),
-        |(_, _, id): (&str, &str, Identifier)| -> Result<Command> { Ok(Command::Peek(id)) },
+    map(
+        verify(
+            tuple((
+                tag("peek"),
+                multispace1,
+                identifier,
+                opt(preceded(space1, operand)),
+                reply,
+            )),
+            |(_, _, _, count, reply)| count.is_none() || *reply == Reply::Value,
+        ),
+        |(_, _, id, count, reply)| match count {
+            Some(count) => Command::Range(id, Expr::Value(0.into()), count),
+            None => Command::Peek(id, reply),
+        },
+    )(input)
+}
+
+fn peek_back(input: &str) -> IResult<&str, Command> {
+    map(
+        tuple((tag("peek_back"), multispace1, identifier, reply)),
+        |(_, _, id, reply)| Command::PeekBack(id, reply),
+    )(input)
+}
+
+fn range(input: &str) -> IResult<&str, Command> {
+    map(
+        tuple((
+            tag("range"),
+            multispace1,
+            identifier,
+            multispace1,
+            operand,
+            multispace1,
+            operand,
+        )),
+        |(_, _, id, _, start, _, count)| Command::Range(id, start, count),
+    )(input)
+}
+
+fn move_value(input: &str) -> IResult<&str, Command> {
+    let timeout = preceded(tuple((multispace1, tag("wait"), multispace1)), operand);
+
+    map(
+        tuple((
+            tag("move"),
+            multispace1,
+            identifier,
+            multispace1,
+            identifier,
+            opt(timeout),
+        )),
+        |(_, _, src, _, dst, timeout)| Command::Move(src, dst, timeout),
+    )(input)
+}
+
+fn purge(input: &str) -> IResult<&str, Command> {
+    map(
+        preceded(pair(tag("purge"), multispace1), identifier),
+        Command::Purge,
+    )(input)
+}
+
+fn delete(input: &str) -> IResult<&str, Command> {
+    map(
+        preceded(pair(tag("delete"), multispace1), identifier),
+        Command::Delete,
+    )(input)
+}
+
+/// Both queues of a command that works on a whole queue, such as `rename`.
+fn queue_pair(input: &str) -> IResult<&str, (Identifier, Identifier)> {
+    preceded(
+        multispace1,
+        separated_pair(identifier, multispace1, identifier),
+    )(input)
+}
+
+fn rename(input: &str) -> IResult<&str, Command> {
+    map(preceded(tag("rename"), queue_pair), |(src, dst)| {
+        Command::Rename(src, dst)
+    })(input)
+}
+
+fn copy(input: &str) -> IResult<&str, Command> {
+    map(preceded(tag("copy"), queue_pair), |(src, dst)| {
+        Command::Copy(src, dst)
+    })(input)
+}
+
+fn queues(input: &str) -> IResult<&str, Command> {
+    map(
+        preceded(tag("queues"), opt(preceded(space1, operand))),
+        Command::Queues,
     )(input)
 }
 
+fn scan(input: &str) -> IResult<&str, Command> {
+    map(
+        tuple((
+            tag("scan"),
+            multispace1,
+            operand,
+            opt(preceded(space1, operand)),
+        )),
+        |(_, _, cursor, pattern)| Command::Scan(cursor, pattern),
+    )(input)
+}
+
+fn info(input: &str) -> IResult<&str, Command> {
+    map(
+        preceded(pair(tag("info"), multispace1), identifier),
+        Command::Info,
+    )(input)
+}
+
+fn overflow(input: &str) -> IResult<&str, Overflow> {
+    alt((
+        value(Overflow::Reject, tag("reject")),
+        value(Overflow::DropOldest, tag("drop_oldest")),
+        value(Overflow::Block, tag("block")),
+    ))(input)
+}
+
+fn mode(input: &str) -> IResult<&str, Mode> {
+    alt((
+        value(Mode::Fifo, tag("fifo")),
+        value(Mode::Stack, tag("stack")),
+    ))(input)
+}
+
+fn value_type(input: &str) -> IResult<&str, ValueType> {
+    preceded(
+        tag(":"),
+        alt((
+            value(ValueType::Integer, tag("integer")),
+            value(ValueType::Float, tag("float")),
+            value(ValueType::String, tag("string")),
+            value(ValueType::Null, tag("null")),
+            value(ValueType::Bool, tag("bool")),
+            value(ValueType::Bytes, tag("bytes")),
+            value(ValueType::List, tag("list")),
+            value(ValueType::Map, tag("map")),
+        )),
+    )(input)
+}
+
+fn setting(input: &str) -> IResult<&str, Setting> {
+    alt((
+        map(
+            preceded(pair(tag("max_length"), multispace1), operand),
+            Setting::MaxLength,
+        ),
+        map(
+            preceded(pair(tag("overflow"), multispace1), overflow),
+            Setting::Overflow,
+        ),
+        map(
+            preceded(pair(tag("dedup_window"), multispace1), operand),
+            Setting::DedupWindow,
+        ),
+        map(
+            preceded(pair(tag("mode"), multispace1), mode),
+            Setting::Mode,
+        ),
+        map(
+            preceded(
+                pair(tag("type"), multispace1),
+                alt((map(value_type, Some), value(None, tag("any")))),
+            ),
+            Setting::Type,
+        ),
+    ))(input)
+}
+
+/// A queue name, or a string glob pattern naming a template.
+fn config_target(input: &str) -> IResult<&str, ConfigTarget> {
+    alt((
+        map(identifier, ConfigTarget::Queue),
+        map(string::parse_string, ConfigTarget::Template),
+    ))(input)
+}
+
+fn open(input: &str) -> IResult<&str, Command> {
+    map(
+        tuple((
+            tag("open"),
+            multispace1,
+            identifier,
+            opt(preceded(space1, value_type)),
+            opt(preceded(tuple((space1, tag("mode"), multispace1)), mode)),
+        )),
+        |(_, _, id, value_type, mode)| Command::Open(id, value_type, mode),
+    )(input)
+}
+
+fn configure(input: &str) -> IResult<&str, Command> {
+    map(
+        tuple((
+            tag("configure"),
+            multispace1,
+            config_target,
+            many1(preceded(space1, setting)),
+        )),
+        |(_, _, target, settings)| Command::Configure(target, settings),
+    )(input)
+}
+
+fn config(input: &str) -> IResult<&str, Command> {
+    map(
+        preceded(pair(tag("config"), multispace1), config_target),
+        Command::Config,
+    )(input)
+}
+
+/// Commands that work on whole queues rather than their values.
+fn admin(input: &str) -> IResult<&str, Command> {
+    alt((
+        purge, delete, rename, copy, queues, scan, info, open, configure, config,
+    ))(input)
+}
+
 fn assert(input: &str) -> IResult<&str, Command> {
     let inner = delimited(tag("("), expr, tag(")"));
     let with_spaces = delimited(multispace1, inner, multispace1);
 
     map_res(
-        tuple((tag("assert"), with_spaces, val)),
-        |(_, cmd, val): (&str, Command, Value)| -> Result<Command> {
+        tuple((tag("assert"), with_spaces, operand)),
+        |(_, cmd, val): (&str, Command, Expr)| -> Result<Command> {
             Ok(Command::Assert(Box::new(cmd), val))
         },
     )(input)
@@ -99,7 +538,7 @@ fn assert(input: &str) -> IResult<&str, Command> {
 
 fn assert_error(input: &str) -> IResult<&str, Command> {
     let inner = delimited(tag("("), expr, tag(")"));
-    let with_spaces = delimited(multispace1, inner, multispace1);
+    let with_spaces = preceded(multispace1, inner);
 
     map_res(
         tuple((tag("assert error"), with_spaces)),
@@ -107,34 +546,200 @@ fn assert_error(input: &str) -> IResult<&str, Command> {
     )(input)
 }
 
+fn let_binding(input: &str) -> IResult<&str, Command> {
+    let equals = delimited(multispace0, char('='), multispace0);
+
+    map(
+        tuple((tag("let"), multispace1, identifier, equals, operand)),
+        |(_, _, id, _, val)| Command::Let(id, val),
+    )(input)
+}
+
 fn comment(input: &str) -> IResult<&str, Command> {
-    value(Command::Noop, pair(char('#'), is_not("\r\n")))(input)
+    map(preceded(char('#'), not_line_ending), |text: &str| {
+        Command::Comment(text.to_string())
+    })(input)
+}
+
+fn comparison(input: &str) -> IResult<&str, Comparison> {
+    alt((
+        value(Comparison::Equal, tag("==")),
+        value(Comparison::NotEqual, tag("!=")),
+        value(Comparison::LessOrEqual, tag("<=")),
+        value(Comparison::GreaterOrEqual, tag(">=")),
+        value(Comparison::Less, tag("<")),
+        value(Comparison::Greater, tag(">")),
+    ))(input)
+}
+
+fn condition(input: &str) -> IResult<&str, Condition> {
+    map(
+        tuple((
+            operand,
+            delimited(multispace0, comparison, multispace0),
+            operand,
+        )),
+        |(left, comparison, right)| Condition {
+            left,
+            comparison,
+            right,
+        },
+    )(input)
+}
+
+/// Separator between the commands of a block: a line ending, or a semicolon
+/// to write a whole block on one line.
+fn statement_separator(input: &str) -> IResult<&str, &str> {
+    alt((line_ending, tag(";")))(input)
+}
+
+/// A block of commands in braces, as in `{ enqueue a 1; dequeue b }`.
+fn block(input: &str) -> IResult<&str, Vec<Command>> {
+    map(
+        delimited(
+            tuple((char('{'), space0, opt(line_ending))),
+            pair(
+                many0(terminated(line, statement_separator)),
+                opt(delimited(space0, expr, space0)),
+            ),
+            pair(multispace0, char('}')),
+        ),
+        |(mut commands, last)| {
+            commands.extend(last);
+            commands
+        },
+    )(input)
+}
+
+fn repeat(input: &str) -> IResult<&str, Command> {
+    map(
+        tuple((tag("repeat"), multispace1, operand, multispace0, block)),
+        |(_, _, count, _, body)| Command::Repeat(count, body),
+    )(input)
+}
+
+fn while_loop(input: &str) -> IResult<&str, Command> {
+    map(
+        tuple((tag("while"), multispace1, condition, multispace0, block)),
+        |(_, _, condition, _, body)| Command::While(condition, body),
+    )(input)
+}
+
+fn if_else(input: &str) -> IResult<&str, Command> {
+    let otherwise = preceded(
+        pair(multispace0, tag("else")),
+        alt((
+            preceded(multispace0, block),
+            preceded(multispace1, map(if_else, |cmd| vec![cmd])),
+        )),
+    );
+
+    map(
+        tuple((
+            tag("if"),
+            multispace1,
+            condition,
+            multispace0,
+            block,
+            opt(otherwise),
+        )),
+        |(_, _, condition, _, then, otherwise)| {
+            Command::If(condition, then, otherwise.unwrap_or_default())
+        },
+    )(input)
+}
+
+fn transaction(input: &str) -> IResult<&str, Command> {
+    alt((
+        value(Command::Begin, tag("begin")),
+        value(Command::Commit, tag("commit")),
+        value(Command::Rollback, tag("rollback")),
+    ))(input)
 }
 
 #[tracing::instrument]
 pub fn expr(input: &str) -> IResult<&str, Command> {
     complete(alt((
         comment,
+        transaction,
+        repeat,
+        while_loop,
+        if_else,
         enqueue,
         dequeue,
+        push_front,
+        pop_back,
         length,
         peek,
+        peek_back,
+        range,
+        move_value,
+        release,
+        admin,
         assert,
         assert_error,
+        let_binding,
     )))(input)
 }
 
+/// A single line of a program. Blank lines are kept as `Noop`, so that
+/// formatting a program preserves them.
+fn line(input: &str) -> IResult<&str, Command> {
+    map(delimited(space0, opt(expr), space0), |cmd| {
+        cmd.unwrap_or(Command::Noop)
+    })(input)
+}
+
 #[tracing::instrument]
 pub fn program(input: &str) -> IResult<&str, Vec<Command>> {
-    many1(terminated(expr, opt(line_ending)))(input)
+    map(
+        pair(
+            many0(terminated(line, line_ending)),
+            opt(delimited(space0, expr, space0)),
+        ),
+        |(mut commands, last)| {
+            commands.extend(last);
+            commands
+        },
+    )(input)
+}
+
+/// The literal at the start of `input`, for error messages.
+fn literal(input: &str) -> String {
+    input
+        .split(|c: char| c.is_whitespace() || ",]}".contains(c))
+        .next()
+        .unwrap_or_default()
+        .to_string()
 }
 
 pub fn parse(input: &str) -> Result<Vec<Command>> {
-    let (_, prg) = program(input).map_err(|_| SyntaxError::ParseError(input.to_string()))?;
+    let (_, prg) = all_consuming(program)(input).map_err(|e| match e {
+        nom::Err::Failure(e) if e.code == nom::error::ErrorKind::TooLarge => {
+            SyntaxError::NumberOutOfRange(literal(e.input))
+        }
+        _ => SyntaxError::ParseError(input.to_string()),
+    })?;
 
     Ok(prg)
 }
 
+/// Parses a single value literal, such as a reply from the server.
+pub fn parse_value(input: &str) -> Result<Value> {
+    let (_, value) = all_consuming(delimited(multispace0, val, multispace0))(input)
+        .map_err(|_| SyntaxError::ParseError(input.to_string()))?;
+
+    Ok(value)
+}
+
+/// Reformats a program in the canonical style, one command per line.
+pub fn format(input: &str) -> Result<String> {
+    Ok(parse(input)?
+        .iter()
+        .map(|command| format!("{}\n", command))
+        .collect())
+}
+
 #[test]
 fn decimal_test() {
     assert_eq!(decimal("1"), Ok(("", 1.into())));
@@ -146,6 +751,34 @@ fn decimal_test() {
     assert!(decimal("a").is_err());
 }
 
+#[test]
+fn integer_test() {
+    assert_eq!(integer("-12"), Ok(("", Value::Integer(-12))));
+    assert_eq!(integer("+12"), Ok(("", Value::Integer(12))));
+    assert_eq!(integer("1_000_000"), Ok(("", Value::Integer(1_000_000))));
+    assert_eq!(integer("0xff"), Ok(("", Value::Integer(255))));
+    assert_eq!(integer("-0x10"), Ok(("", Value::Integer(-16))));
+    assert_eq!(integer("0o17"), Ok(("", Value::Integer(15))));
+    assert_eq!(integer("0b1010"), Ok(("", Value::Integer(10))));
+    assert_eq!(
+        integer("9223372036854775807"),
+        Ok(("", Value::Integer(i64::MAX)))
+    );
+    assert_eq!(
+        integer("-9223372036854775808"),
+        Ok(("", Value::Integer(i64::MIN)))
+    );
+    assert!(integer("1.5").is_err());
+    assert!(matches!(
+        integer("9223372036854775808"),
+        Err(nom::Err::Failure(_))
+    ));
+    assert!(matches!(
+        integer("0x1_0000_0000_0000_0000"),
+        Err(nom::Err::Failure(_))
+    ));
+}
+
 #[test]
 fn float_test() {
     assert_eq!(float("1.0"), Ok(("", (1.0).into())));
@@ -153,6 +786,29 @@ fn float_test() {
     assert_eq!(float("4.0"), Ok(("", (4.0).into())));
     assert_eq!(float("5.0"), Ok(("", (5.0).into())));
     assert!(float("a").is_err());
+    assert_eq!(float("1.16e12"), Ok(("", Value::Float(1.16e12))));
+    assert_eq!(float("-0.1"), Ok(("", Value::Float(-0.1))));
+    assert_eq!(float("2E-3"), Ok(("", Value::Float(2e-3))));
+    assert_eq!(float("1_000.5"), Ok(("", Value::Float(1000.5))));
+    assert_eq!(float("inf"), Ok(("", Value::Float(f64::INFINITY))));
+    assert_eq!(float("-inf"), Ok(("", Value::Float(f64::NEG_INFINITY))));
+    assert!(float("1").is_err());
+    assert!(float("nan").is_err());
+    assert!(matches!(float("1e999"), Err(nom::Err::Failure(_))));
+}
+
+#[test]
+fn number_out_of_range_test() -> Result<()> {
+    assert_eq!(
+        parse("enqueue a 99999999999999999999")
+            .unwrap_err()
+            .downcast::<SyntaxError>()
+            .unwrap(),
+        SyntaxError::NumberOutOfRange("99999999999999999999".into())
+    );
+    assert_eq!(parse("enqueue a 1.5")?, vec![Command::enqueue("a", 1.5)]);
+
+    Ok(())
 }
 
 #[test]
@@ -164,6 +820,50 @@ fn null_test() {
     );
 }
 
+#[test]
+fn structured_value_test() {
+    assert_eq!(boolean("true"), Ok(("", true.into())));
+    assert_eq!(boolean("false"), Ok(("", false.into())));
+    assert_eq!(val("b\"a\\x00\""), Ok(("", b"a\0".to_vec().into())));
+    assert_eq!(val("[]"), Ok(("", Value::List(vec![]))));
+    assert_eq!(
+        val("[1, \"a\" ,[null]]"),
+        Ok((
+            "",
+            Value::List(vec![
+                1.into(),
+                "a".to_string().into(),
+                vec![Value::Null].into()
+            ])
+        ))
+    );
+    assert_eq!(
+        val("{id: 1, \"trace id\": \"abc\"}"),
+        Ok((
+            "",
+            Value::Map(
+                vec![
+                    ("id".to_string(), 1.into()),
+                    ("trace id".to_string(), "abc".to_string().into())
+                ]
+                .into_iter()
+                .collect()
+            )
+        ))
+    );
+    assert!(val("[1, 2").is_err());
+    assert_eq!(
+        expr("enqueue a {ok: true}"),
+        Ok((
+            "",
+            Command::enqueue(
+                "a",
+                Value::Map(vec![("ok".to_string(), true.into())].into_iter().collect())
+            )
+        ))
+    );
+}
+
 #[test]
 fn expr_test() {
     assert_eq!(
@@ -173,13 +873,487 @@ fn expr_test() {
     assert_eq!(expr("dequeue omg"), Ok(("", Command::dequeue("omg"))));
     assert_eq!(expr("length omg"), Ok(("", Command::length("omg"))));
     assert_eq!(expr("peek omg"), Ok(("", Command::peek("omg"))));
+    assert_eq!(
+        expr("push_front omg 1"),
+        Ok((
+            "",
+            Command::PushFront("omg".into(), Expr::Value(1.into()), None)
+        ))
+    );
+    assert_eq!(
+        expr("pop_back omg full"),
+        Ok(("", Command::PopBack("omg".into(), Reply::Full)))
+    );
+    assert_eq!(
+        expr("peek_back omg"),
+        Ok(("", Command::PeekBack("omg".into(), Reply::Value)))
+    );
+    assert_eq!(
+        expr("enqueue a 1 group customer42"),
+        Ok((
+            "",
+            Command::Enqueue {
+                queue: "a".into(),
+                value: Expr::Value(1.into()),
+                headers: None,
+                dedup: None,
+                group: Some(Expr::Value(Value::String("customer42".into()))),
+                wait: None,
+            }
+        ))
+    );
+    assert_eq!(
+        expr("release a \"customer 42\""),
+        Ok((
+            "",
+            Command::Release("a".into(), Expr::Value(Value::String("customer 42".into())))
+        ))
+    );
+    assert!(parse("release a true").is_err());
+    assert!(parse("enqueue g 1 group true").is_err());
+    assert!(parse("enqueue g 1 group 5").is_err());
+    assert_eq!(
+        expr("release a $g"),
+        Ok(("", Command::Release("a".into(), Expr::Variable("g".into()))))
+    );
+    assert_eq!(
+        expr("range a 1 $n"),
+        Ok((
+            "",
+            Command::Range(
+                "a".into(),
+                Expr::Value(1.into()),
+                Expr::Variable("n".into())
+            )
+        ))
+    );
+    assert_eq!(
+        expr("peek a 5"),
+        Ok((
+            "",
+            Command::Range("a".into(), Expr::Value(0.into()), Expr::Value(5.into()))
+        ))
+    );
+    assert!(parse("peek a 5 full").is_err());
+    assert_eq!(
+        expr("move a b"),
+        Ok(("", Command::Move("a".into(), "b".into(), None)))
+    );
+    assert_eq!(
+        expr("move a b wait 1.5"),
+        Ok((
+            "",
+            Command::Move("a".into(), "b".into(), Some(Expr::Value(1.5.into())))
+        ))
+    );
+    assert_eq!(expr("purge a"), Ok(("", Command::Purge("a".into()))));
+    assert_eq!(expr("delete a"), Ok(("", Command::Delete("a".into()))));
+    assert_eq!(
+        expr("rename a  b"),
+        Ok(("", Command::Rename("a".into(), "b".into())))
+    );
+    assert_eq!(
+        expr("copy a b"),
+        Ok(("", Command::Copy("a".into(), "b".into())))
+    );
+    assert_eq!(expr("queues"), Ok(("", Command::Queues(None))));
+    assert_eq!(
+        expr("queues \"jobs_*\""),
+        Ok((
+            "",
+            Command::Queues(Some(Expr::Value(Value::String("jobs_*".into()))))
+        ))
+    );
+    assert_eq!(
+        expr("scan null"),
+        Ok(("", Command::Scan(Expr::Value(Value::Null), None)))
+    );
+    assert_eq!(expr("info a"), Ok(("", Command::Info("a".into()))));
+    assert_eq!(
+        expr("configure a max_length 10 overflow drop_oldest dedup_window 60 mode stack"),
+        Ok((
+            "",
+            Command::Configure(
+                ConfigTarget::Queue("a".into()),
+                vec![
+                    Setting::MaxLength(Expr::Value(10.into())),
+                    Setting::Overflow(Overflow::DropOldest),
+                    Setting::DedupWindow(Expr::Value(60.into())),
+                    Setting::Mode(Mode::Stack),
+                ]
+            )
+        ))
+    );
+    assert!(expr("configure a").is_err());
+    assert_eq!(
+        expr("configure a type :integer type any"),
+        Ok((
+            "",
+            Command::Configure(
+                ConfigTarget::Queue("a".into()),
+                vec![Setting::Type(Some(ValueType::Integer)), Setting::Type(None)]
+            )
+        ))
+    );
+    assert_eq!(
+        expr("open a"),
+        Ok(("", Command::Open("a".into(), None, None)))
+    );
+    assert_eq!(
+        expr("open a :integer mode stack"),
+        Ok((
+            "",
+            Command::Open("a".into(), Some(ValueType::Integer), Some(Mode::Stack))
+        ))
+    );
+    assert_eq!(
+        expr("open a mode stack"),
+        Ok(("", Command::Open("a".into(), None, Some(Mode::Stack))))
+    );
+    assert_eq!(
+        expr("config \"orders_*\""),
+        Ok((
+            "",
+            Command::Config(ConfigTarget::Template("orders_*".into()))
+        ))
+    );
+    assert_eq!(expr("begin"), Ok(("", Command::Begin)));
+    assert_eq!(expr("commit"), Ok(("", Command::Commit)));
+    assert_eq!(expr("rollback"), Ok(("", Command::Rollback)));
     assert_eq!(
         expr("assert (peek omg) 1"),
         Ok((
             "",
-            Command::Assert(Box::new(Command::peek("omg")), 1.into())
+            Command::Assert(Box::new(Command::peek("omg")), Expr::Value(1.into()))
+        ))
+    );
+}
+
+#[test]
+fn format_test() -> Result<()> {
+    assert_eq!(
+        format(
+            "# queue a\n  enqueue   a 1.0\n\nassert (peek a)   1.\
+0\n"
+        )?,
+        "# queue a\nenqueue a 1.0\n\nassert (peek a) 1.0\n"
+    );
+    assert!(format("enqueue a 1\nnot a command").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn block_test() -> Result<()> {
+    let condition = Condition {
+        left: Command::length("a").into(),
+        comparison: Comparison::Greater,
+        right: Expr::Value(0.into()),
+    };
+
+    assert_eq!(
+        parse("repeat 3 { enqueue a 1 }")?,
+        vec![Command::Repeat(
+            Expr::Value(3.into()),
+            vec![Command::enqueue("a", 1)]
+        )]
+    );
+    assert_eq!(
+        parse("while (length a) > 0 { dequeue a; dequeue a }")?,
+        vec![Command::While(
+            condition.clone(),
+            vec![Command::dequeue("a"), Command::dequeue("a")]
+        )]
+    );
+    assert_eq!(
+        parse("if (length a)>0 {\n  dequeue a\n} else if $x == 1 {} else {\n}")?,
+        vec![Command::If(
+            condition,
+            vec![Command::dequeue("a")],
+            vec![Command::If(
+                Condition {
+                    left: Expr::Variable("x".into()),
+                    comparison: Comparison::Equal,
+                    right: Expr::Value(1.into()),
+                },
+                vec![],
+                vec![]
+            )]
+        )]
+    );
+    assert!(parse("if (length a) { dequeue a }").is_err());
+    assert!(parse("repeat 3 { enqueue a 1").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn format_block_test() -> Result<()> {
+    assert_eq!(
+        format("repeat 2 { if $x<=1 { enqueue a $x } else { dequeue a }; length a }")?,
+        "repeat 2 {\n    if $x <= 1 {\n        enqueue a $x\n    } else {\n        dequeue a\n    }\n    length a\n}\n"
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+use proptest::prelude::*;
+
+#[cfg(test)]
+fn arb_value() -> impl Strategy<Value = Value> {
+    let leaf = prop_oneof![
+        any::<i64>().prop_map(Value::Integer),
+        any::<f64>()
+            .prop_filter("nan has no literal", |v| !v.is_nan())
+            .prop_map(Value::Float),
+        any::<String>().prop_map(Value::String),
+        Just(Value::Null),
+        any::<bool>().prop_map(Value::Bool),
+        any::<Vec<u8>>().prop_map(Value::Bytes),
+    ];
+
+    leaf.prop_recursive(3, 32, 4, |inner| {
+        prop_oneof![
+            prop::collection::vec(inner.clone(), 0..4).prop_map(Value::List),
+            prop::collection::btree_map(any::<String>(), inner, 0..4).prop_map(Value::Map),
+        ]
+    })
+}
+
+#[cfg(test)]
+fn arb_identifier() -> impl Strategy<Value = Identifier> {
+    "[a-zA-Z_][a-zA-Z0-9_]{0,8}".prop_map(Identifier)
+}
+
+#[cfg(test)]
+fn arb_expr() -> impl Strategy<Value = Expr> {
+    prop_oneof![
+        arb_value().prop_map(Expr::Value),
+        arb_identifier().prop_map(Expr::Variable),
+        arb_identifier().prop_map(|id| Command::dequeue(id).into()),
+    ]
+}
+
+#[cfg(test)]
+fn arb_group() -> impl Strategy<Value = Expr> {
+    prop_oneof![
+        arb_identifier().prop_map(|id| Expr::Value(Value::String(id.0))),
+        any::<String>().prop_map(|group| Expr::Value(Value::String(group))),
+        arb_identifier().prop_map(Expr::Variable),
+        arb_identifier().prop_map(|id| Command::dequeue(id).into()),
+    ]
+}
+
+#[cfg(test)]
+fn arb_queue_command() -> impl Strategy<Value = Command> {
+    let id = arb_identifier;
+
+    prop_oneof![
+        (id(), arb_expr(), prop::option::of(arb_expr())).prop_map(|(queue, value, headers)| {
+            Command::Enqueue {
+                queue,
+                value,
+                headers,
+                dedup: None,
+                group: None,
+                wait: None,
+            }
+        }),
+        (id(), arb_expr(), arb_expr(), prop::option::of(arb_group())).prop_map(
+            |(queue, value, dedup, group)| Command::Enqueue {
+                queue,
+                value,
+                headers: None,
+                dedup: Some(dedup),
+                group,
+                wait: None,
+            }
+        ),
+        (id(), arb_expr(), arb_expr()).prop_map(|(queue, value, wait)| Command::Enqueue {
+            queue,
+            value,
+            headers: None,
+            dedup: None,
+            group: None,
+            wait: Some(wait),
+        }),
+        (id(), arb_reply()).prop_map(|(id, reply)| Command::Dequeue(id, reply)),
+        (id(), arb_expr(), prop::option::of(arb_expr()))
+            .prop_map(|(id, v, headers)| Command::PushFront(id, v, headers)),
+        (id(), arb_reply()).prop_map(|(id, reply)| Command::PopBack(id, reply)),
+        id().prop_map(Command::Length),
+        (id(), arb_reply()).prop_map(|(id, reply)| Command::Peek(id, reply)),
+        (id(), arb_reply()).prop_map(|(id, reply)| Command::PeekBack(id, reply)),
+        (id(), arb_expr(), arb_expr())
+            .prop_map(|(id, start, count)| Command::Range(id, start, count)),
+        (id(), id(), prop::option::of(arb_expr()))
+            .prop_map(|(src, dst, timeout)| Command::Move(src, dst, timeout)),
+        (id(), arb_group()).prop_map(|(id, group)| Command::Release(id, group)),
+        id().prop_map(Command::Purge),
+        id().prop_map(Command::Delete),
+        (id(), id()).prop_map(|(src, dst)| Command::Rename(src, dst)),
+        (id(), id()).prop_map(|(src, dst)| Command::Copy(src, dst)),
+        prop::option::of(arb_expr()).prop_map(Command::Queues),
+        (arb_expr(), prop::option::of(arb_expr()))
+            .prop_map(|(cursor, pattern)| Command::Scan(cursor, pattern)),
+        id().prop_map(Command::Info),
+        (
+            id(),
+            prop::option::of(arb_value_type()),
+            prop::option::of(arb_mode())
+        )
+            .prop_map(|(id, value_type, mode)| Command::Open(id, value_type, mode)),
+        (
+            arb_config_target(),
+            prop::collection::vec(arb_setting(), 1..3)
+        )
+            .prop_map(|(target, settings)| Command::Configure(target, settings)),
+        arb_config_target().prop_map(Command::Config),
+    ]
+}
+
+#[cfg(test)]
+fn arb_reply() -> impl Strategy<Value = Reply> {
+    prop_oneof![Just(Reply::Value), Just(Reply::Full)]
+}
+
+#[cfg(test)]
+fn arb_config_target() -> impl Strategy<Value = ConfigTarget> {
+    prop_oneof![
+        arb_identifier().prop_map(ConfigTarget::Queue),
+        any::<String>().prop_map(ConfigTarget::Template),
+    ]
+}
+
+#[cfg(test)]
+fn arb_setting() -> impl Strategy<Value = Setting> {
+    prop_oneof![
+        arb_expr().prop_map(Setting::MaxLength),
+        prop_oneof![
+            Just(Overflow::Reject),
+            Just(Overflow::DropOldest),
+            Just(Overflow::Block),
+        ]
+        .prop_map(Setting::Overflow),
+        arb_expr().prop_map(Setting::DedupWindow),
+        arb_mode().prop_map(Setting::Mode),
+        prop::option::of(arb_value_type()).prop_map(Setting::Type),
+    ]
+}
+
+#[cfg(test)]
+fn arb_mode() -> impl Strategy<Value = Mode> {
+    prop_oneof![Just(Mode::Fifo), Just(Mode::Stack)]
+}
+
+#[cfg(test)]
+fn arb_value_type() -> impl Strategy<Value = ValueType> {
+    prop_oneof![
+        Just(ValueType::Integer),
+        Just(ValueType::Float),
+        Just(ValueType::String),
+        Just(ValueType::Null),
+        Just(ValueType::Bool),
+        Just(ValueType::Bytes),
+        Just(ValueType::List),
+        Just(ValueType::Map),
+    ]
+}
+
+#[cfg(test)]
+fn arb_condition() -> impl Strategy<Value = Condition> {
+    let comparison = prop_oneof![
+        Just(Comparison::Equal),
+        Just(Comparison::NotEqual),
+        Just(Comparison::Less),
+        Just(Comparison::LessOrEqual),
+        Just(Comparison::Greater),
+        Just(Comparison::GreaterOrEqual),
+    ];
+
+    (arb_expr(), comparison, arb_expr()).prop_map(|(left, comparison, right)| Condition {
+        left,
+        comparison,
+        right,
+    })
+}
+
+#[cfg(test)]
+fn arb_command() -> impl Strategy<Value = Command> {
+    let leaf = prop_oneof![
+        arb_queue_command(),
+        (arb_queue_command(), arb_expr()).prop_map(|(cmd, v)| Command::Assert(Box::new(cmd), v)),
+        (arb_identifier(), arb_expr()).prop_map(|(id, v)| Command::Let(id, v)),
+        arb_queue_command().prop_map(|cmd| Command::AssertError(Box::new(cmd))),
+        Just(Command::Begin),
+        Just(Command::Commit),
+        Just(Command::Rollback),
+        "[^\r\n]*".prop_map(Command::Comment),
+    ];
+
+    leaf.prop_recursive(3, 16, 4, |inner| {
+        let body = prop::collection::vec(prop_oneof![inner, Just(Command::Noop)], 0..4);
+
+        prop_oneof![
+            (arb_expr(), body.clone()).prop_map(|(count, body)| Command::Repeat(count, body)),
+            (arb_condition(), body.clone()).prop_map(|(cond, body)| Command::While(cond, body)),
+            (arb_condition(), body.clone(), body)
+                .prop_map(|(cond, then, otherwise)| Command::If(cond, then, otherwise)),
+        ]
+    })
+}
+
+#[cfg(test)]
+proptest! {
+    #[test]
+    fn printed_commands_parse_back(command in arb_command()) {
+        prop_assert_eq!(parse(&command.to_string()).unwrap(), vec![command]);
+    }
+
+    #[test]
+    fn formatted_programs_parse_back(
+        commands in prop::collection::vec(prop_oneof![arb_command(), Just(Command::Noop)], 0..8)
+    ) {
+        let source: String = commands.iter().map(|c| format!("{}\n", c)).collect();
+
+        prop_assert_eq!(parse(&source).unwrap(), commands);
+        prop_assert_eq!(format(&source).unwrap(), source);
+    }
+}
+
+#[test]
+fn variables_test() {
+    assert_eq!(
+        expr("let x = (dequeue a)"),
+        Ok(("", Command::Let("x".into(), Command::dequeue("a").into())))
+    );
+    assert_eq!(
+        expr("let x=1"),
+        Ok(("", Command::Let("x".into(), Expr::Value(1.into()))))
+    );
+    assert_eq!(
+        expr("enqueue b $x"),
+        Ok((
+            "",
+            Command::Enqueue {
+                queue: "b".into(),
+                value: Expr::Variable("x".into()),
+                headers: None,
+                dedup: None,
+                group: None,
+                wait: None,
+            }
+        ))
+    );
+    assert_eq!(
+        expr("assert (peek b) $x"),
+        Ok((
+            "",
+            Command::Assert(Box::new(Command::peek("b")), Expr::Variable("x".into()))
         ))
     );
+    assert!(expr("enqueue b $").is_err());
 }
 
 #[test]
@@ -0,0 +1,511 @@
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::broadcast;
+use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+use tracing::{info, warn};
+
+use crate::errors::*;
+use crate::storage::{
+    Enqueued, Envelope, Headers, Message, Notifier, QueueConfig, QueueInfo, Snapshot,
+    StorageBackend, Templates, Transaction, Write,
+};
+use crate::types::*;
+
+/// How many mutations a replica may fall behind before it is disconnected
+/// and has to start over from a fresh snapshot.
+const REPLICATION_BUFFER: usize = 16 * 1024;
+
+/// A change to the storage, as streamed from a primary to its replicas.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Mutation {
+    Enqueue(Identifier, Message),
+    PushFront(Identifier, Message),
+    Dequeue(Identifier),
+    PopBack(Identifier),
+    Purge(Identifier),
+    Delete(Identifier),
+    Rename(Identifier, Identifier),
+    Copy(Identifier, Identifier),
+    Release(Identifier, String),
+    /// The writes of a committed transaction, applied all at once.
+    Transaction(Vec<Write>),
+    /// The new settings of a template.
+    Template(String, QueueConfig),
+}
+
+/// What a primary sends its replicas: a snapshot to start from, then every
+/// mutation after it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Frame {
+    Snapshot(Snapshot),
+    Mutation(Mutation),
+}
+
+/// Wraps a storage backend so that every mutation applied to it is streamed
+/// to the connected replicas, in the order it was applied.
+///
+/// A replica rejects writes from clients and only changes through `apply`
+/// and `restore`, until it is promoted.
+#[derive(Debug, Clone)]
+pub struct ReplicatedStorage<T> {
+    storage: T,
+    replica: Arc<AtomicBool>,
+    log: Arc<Mutex<()>>,
+    sender: broadcast::Sender<Mutation>,
+}
+
+impl<T: StorageBackend + Debug> ReplicatedStorage<T> {
+    pub fn primary(storage: T) -> Self {
+        Self::new(storage, false)
+    }
+
+    pub fn replica(storage: T) -> Self {
+        Self::new(storage, true)
+    }
+
+    fn new(storage: T, replica: bool) -> Self {
+        let (sender, _) = broadcast::channel(REPLICATION_BUFFER);
+
+        Self {
+            storage,
+            replica: Arc::new(AtomicBool::new(replica)),
+            log: Arc::new(Mutex::new(())),
+            sender,
+        }
+    }
+
+    pub fn is_replica(&self) -> bool {
+        self.replica.load(Ordering::SeqCst)
+    }
+
+    /// Turns a replica into a primary: it stops following its primary and
+    /// starts accepting writes.
+    pub fn promote(&self) {
+        self.replica.store(false, Ordering::SeqCst);
+    }
+
+    /// Returns a snapshot of the storage along with a subscription to every
+    /// mutation applied after it.
+    pub fn subscribe(&self) -> Result<(Snapshot, broadcast::Receiver<Mutation>)> {
+        if self.is_replica() {
+            bail!(ReplicationError::NotPrimary);
+        }
+
+        let _log = self.log.lock().map_err(|_| StorageError::FailedLock)?;
+        Ok((self.storage.snapshot()?, self.sender.subscribe()))
+    }
+
+    /// Applies a mutation streamed from the primary.
+    pub fn apply(&self, mutation: Mutation) -> Result<()> {
+        match mutation.clone() {
+            Mutation::Enqueue(id, message) => self.write(mutation, |s| s.push(&id, message)),
+            Mutation::PushFront(id, message) => {
+                self.write(mutation, |s| s.push_front(&id, message))
+            }
+            Mutation::Dequeue(id) => self.write(mutation, |s| s.dequeue(&id)).map(|_| ()),
+            Mutation::PopBack(id) => self.write(mutation, |s| s.pop_back(&id)).map(|_| ()),
+            Mutation::Purge(id) => self.write(mutation, |s| s.purge(&id)).map(|_| ()),
+            Mutation::Delete(id) => self.write(mutation, |s| s.delete(&id)).map(|_| ()),
+            Mutation::Rename(src, dst) => self.write(mutation, |s| s.rename(&src, &dst)),
+            Mutation::Copy(src, dst) => self.write(mutation, |s| s.copy(&src, &dst)),
+            Mutation::Release(id, group) => {
+                self.write(mutation, |s| s.release(&id, &group)).map(|_| ())
+            }
+            Mutation::Transaction(writes) => self
+                .write(mutation, |s| {
+                    s.transaction(&mut |tx| {
+                        for write in writes.iter().cloned() {
+                            tx.apply(write)?;
+                        }
+
+                        Ok(())
+                    })
+                })
+                .map(|_| ()),
+            Mutation::Template(pattern, config) => self.write(mutation, |s| {
+                s.update_template(&pattern, &mut |current| *current = config.clone())
+            }),
+        }
+    }
+
+    fn write<R>(&self, mutation: Mutation, f: impl FnOnce(&T) -> Result<R>) -> Result<R> {
+        let _log = self.log.lock().map_err(|_| StorageError::FailedLock)?;
+        let result = f(&self.storage)?;
+
+        // Sending only fails when there are no replicas listening.
+        let _ = self.sender.send(mutation);
+
+        Ok(result)
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.is_replica() {
+            bail!(ReplicationError::ReadOnly);
+        }
+
+        Ok(())
+    }
+}
+
+impl<T: StorageBackend + Debug> StorageBackend for ReplicatedStorage<T> {
+    fn enqueue_message(
+        &self,
+        id: &Identifier,
+        value: Value,
+        envelope: Envelope,
+    ) -> Result<Enqueued> {
+        self.check_writable()?;
+
+        // The id of the message is only known once it is enqueued, so the
+        // whole message is sent for replicas to push as it is.
+        let _log = self.log.lock().map_err(|_| StorageError::FailedLock)?;
+        let enqueued = self.storage.enqueue_message(id, value, envelope)?;
+
+        if let Enqueued::Message(message) = &enqueued {
+            let _ = self
+                .sender
+                .send(Mutation::Enqueue(id.clone(), message.clone()));
+        }
+
+        Ok(enqueued)
+    }
+
+    fn push(&self, id: &Identifier, message: Message) -> Result<()> {
+        self.check_writable()?;
+        self.write(Mutation::Enqueue(id.clone(), message.clone()), |s| {
+            s.push(id, message)
+        })
+    }
+
+    fn enqueue_front(&self, id: &Identifier, value: Value, headers: Headers) -> Result<Message> {
+        self.check_writable()?;
+
+        let _log = self.log.lock().map_err(|_| StorageError::FailedLock)?;
+        let message = self.storage.enqueue_front(id, value, headers)?;
+        let _ = self
+            .sender
+            .send(Mutation::PushFront(id.clone(), message.clone()));
+
+        Ok(message)
+    }
+
+    fn push_front(&self, id: &Identifier, message: Message) -> Result<()> {
+        self.check_writable()?;
+        self.write(Mutation::PushFront(id.clone(), message.clone()), |s| {
+            s.push_front(id, message)
+        })
+    }
+
+    fn dequeue(&self, id: &Identifier) -> Result<Option<Message>> {
+        self.check_writable()?;
+        self.write(Mutation::Dequeue(id.clone()), |s| s.dequeue(id))
+    }
+
+    fn pop_back(&self, id: &Identifier) -> Result<Option<Message>> {
+        self.check_writable()?;
+        self.write(Mutation::PopBack(id.clone()), |s| s.pop_back(id))
+    }
+
+    fn length(&self, id: &Identifier) -> Result<usize> {
+        self.storage.length(id)
+    }
+
+    fn peek(&self, id: &Identifier) -> Result<Option<Message>> {
+        self.storage.peek(id)
+    }
+
+    fn peek_back(&self, id: &Identifier) -> Result<Option<Message>> {
+        self.storage.peek_back(id)
+    }
+
+    fn range(&self, id: &Identifier, start: usize, count: usize) -> Result<Vec<Message>> {
+        self.storage.range(id, start, count)
+    }
+
+    fn purge(&self, id: &Identifier) -> Result<usize> {
+        self.check_writable()?;
+        self.write(Mutation::Purge(id.clone()), |s| s.purge(id))
+    }
+
+    fn delete(&self, id: &Identifier) -> Result<bool> {
+        self.check_writable()?;
+        self.write(Mutation::Delete(id.clone()), |s| s.delete(id))
+    }
+
+    fn rename(&self, src: &Identifier, dst: &Identifier) -> Result<()> {
+        self.check_writable()?;
+        self.write(Mutation::Rename(src.clone(), dst.clone()), |s| {
+            s.rename(src, dst)
+        })
+    }
+
+    fn copy(&self, src: &Identifier, dst: &Identifier) -> Result<()> {
+        self.check_writable()?;
+        self.write(Mutation::Copy(src.clone(), dst.clone()), |s| {
+            s.copy(src, dst)
+        })
+    }
+
+    fn scan(&self, after: Option<&Identifier>, count: usize) -> Result<Vec<Identifier>> {
+        self.storage.scan(after, count)
+    }
+
+    fn info(&self, id: &Identifier) -> Result<Option<QueueInfo>> {
+        self.storage.info(id)
+    }
+
+    fn config(&self, id: &Identifier) -> Result<QueueConfig> {
+        self.storage.config(id)
+    }
+
+    fn templates(&self) -> Result<Templates> {
+        self.storage.templates()
+    }
+
+    fn update_template(&self, pattern: &str, f: &mut dyn FnMut(&mut QueueConfig)) -> Result<()> {
+        self.check_writable()?;
+
+        // The resulting settings are only known once `f` has run, so they
+        // are sent rather than `f` itself.
+        let _log = self.log.lock().map_err(|_| StorageError::FailedLock)?;
+        let mut updated = QueueConfig::default();
+
+        self.storage.update_template(pattern, &mut |config| {
+            f(config);
+            updated = config.clone();
+        })?;
+
+        let _ = self
+            .sender
+            .send(Mutation::Template(pattern.to_string(), updated));
+
+        Ok(())
+    }
+
+    fn release(&self, id: &Identifier, group: &str) -> Result<bool> {
+        self.check_writable()?;
+        self.write(Mutation::Release(id.clone(), group.to_string()), |s| {
+            s.release(id, group)
+        })
+    }
+
+    fn snapshot(&self) -> Result<Snapshot> {
+        self.storage.snapshot()
+    }
+
+    fn restore(&self, snapshot: Snapshot) -> Result<()> {
+        let _log = self.log.lock().map_err(|_| StorageError::FailedLock)?;
+        self.storage.restore(snapshot)
+    }
+
+    fn transaction(&self, f: &mut dyn FnMut(&Transaction) -> Result<()>) -> Result<Vec<Write>> {
+        self.check_writable()?;
+
+        let _log = self.log.lock().map_err(|_| StorageError::FailedLock)?;
+        let writes = self.storage.transaction(f)?;
+
+        if !writes.is_empty() {
+            let _ = self.sender.send(Mutation::Transaction(writes.clone()));
+        }
+
+        Ok(writes)
+    }
+
+    fn notifier(&self) -> &Notifier {
+        self.storage.notifier()
+    }
+}
+
+/// Streams a snapshot and then every following mutation to a replica.
+///
+/// Returns once the replica disconnects, or falls too far behind.
+#[tracing::instrument(skip(writer))]
+pub async fn serve_replica<T, W>(mut writer: W, storage: ReplicatedStorage<T>) -> Result<()>
+where
+    T: StorageBackend + Debug,
+    W: AsyncWrite + Unpin,
+{
+    let (snapshot, mut mutations) = match storage.subscribe() {
+        Ok(subscription) => subscription,
+        Err(e) => {
+            writer
+                .write_all(format!("ERROR: {}\n", e).as_bytes())
+                .await?;
+            return Err(e);
+        }
+    };
+
+    writer.write_all(b"OK\n").await?;
+
+    let mut frames = FramedWrite::new(writer, LengthDelimitedCodec::new());
+    frames
+        .send(Bytes::from(bincode::serialize(&Frame::Snapshot(snapshot))?))
+        .await?;
+
+    loop {
+        let mutation = mutations.recv().await?;
+
+        frames
+            .send(Bytes::from(bincode::serialize(&Frame::Mutation(mutation))?))
+            .await?;
+    }
+}
+
+async fn follow_once<T: StorageBackend + Debug>(
+    primary: &str,
+    storage: &ReplicatedStorage<T>,
+) -> Result<()> {
+    let mut socket = TcpStream::connect(primary).await?;
+    socket.write_all(b"replicate\n").await?;
+
+    let mut reader = BufReader::new(socket);
+    let mut status = String::new();
+    reader.read_line(&mut status).await?;
+
+    if status.trim_end() != "OK" {
+        bail!(ReplicationError::Refused(status.trim_end().to_string()));
+    }
+
+    let mut frames = FramedRead::new(reader, LengthDelimitedCodec::new());
+
+    while let Some(frame) = frames.next().await {
+        if !storage.is_replica() {
+            break;
+        }
+
+        match bincode::deserialize::<Frame>(&frame?)? {
+            Frame::Snapshot(snapshot) => {
+                info!(
+                    queues = snapshot.queues.len(),
+                    "Restoring snapshot from primary"
+                );
+                storage.restore(snapshot)?
+            }
+            Frame::Mutation(mutation) => storage.apply(mutation)?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Keeps `storage` in sync with the primary at `primary`, reconnecting and
+/// starting over from a fresh snapshot whenever the connection is lost.
+///
+/// Returns once the replica is promoted.
+#[tracing::instrument(skip(storage))]
+pub async fn follow<T: StorageBackend + Debug>(
+    primary: String,
+    storage: ReplicatedStorage<T>,
+) -> Result<()> {
+    while storage.is_replica() {
+        match follow_once(&primary, &storage).await {
+            Ok(()) => warn!("Primary closed the replication stream"),
+            Err(e) => warn!(error = %e, "Replication from primary failed"),
+        }
+
+        if storage.is_replica() {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    info!("Promoted to primary, stopped following");
+
+    Ok(())
+}
+
+#[cfg(all(test, feature = "memory-storage"))]
+use crate::storage::Storage;
+
+#[cfg(all(test, feature = "memory-storage"))]
+#[test]
+fn replica_mirrors_primary_mutations() -> Result<()> {
+    let primary = ReplicatedStorage::primary(Storage::new());
+    primary.enqueue(&"a".into(), 1.into())?;
+
+    let (snapshot, mut mutations) = primary.subscribe()?;
+    primary.enqueue(&"a".into(), 2.into())?;
+    primary.dequeue(&"a".into())?;
+    primary.transaction(&mut |tx| {
+        let message = tx.dequeue(&"a".into())?.unwrap();
+        tx.push(&"b".into(), message)
+    })?;
+    primary.copy(&"b".into(), &"c".into())?;
+    primary.rename(&"c".into(), &"d".into())?;
+    primary.delete(&"a".into())?;
+    primary.update_template("e*", &mut |config| config.max_length = Some(1))?;
+    primary.update_config(&"d".into(), &mut |config| config.max_length = Some(5))?;
+
+    // Replicas replay dequeues, so they must skip the same held groups.
+    let group = Envelope {
+        group: Some("x".into()),
+        ..Envelope::default()
+    };
+    primary.enqueue_message(&"g".into(), 1.into(), group.clone())?;
+    primary.enqueue_message(&"g".into(), 2.into(), group)?;
+    primary.enqueue(&"g".into(), 3.into())?;
+    primary.dequeue(&"g".into())?;
+    primary.dequeue(&"g".into())?;
+    primary.release(&"g".into(), "x")?;
+    primary.enqueue_message(
+        &"g".into(),
+        4.into(),
+        Envelope {
+            group: Some("y".into()),
+            ..Envelope::default()
+        },
+    )?;
+    primary.dequeue(&"g".into())?;
+
+    primary.enqueue_front(&"h".into(), 1.into(), Headers::new())?;
+    primary.enqueue_front(&"h".into(), 2.into(), Headers::new())?;
+    primary.enqueue(&"h".into(), 3.into())?;
+    primary.pop_back(&"h".into())?;
+
+    let replica = ReplicatedStorage::replica(Storage::new());
+    replica.restore(snapshot)?;
+
+    while let Ok(mutation) = mutations.try_recv() {
+        replica.apply(mutation)?;
+    }
+
+    // Metadata is recorded with each node's own clock, so only the values
+    // are expected to match exactly.
+    assert_eq!(replica.snapshot()?.queues, primary.snapshot()?.queues);
+    assert_eq!(replica.peek(&"b".into())?, primary.peek(&"b".into())?);
+    assert_eq!(replica.snapshot()?.next_id, primary.snapshot()?.next_id);
+    assert_eq!(replica.templates()?, primary.templates()?);
+    assert_eq!(replica.config(&"d".into())?.max_length, Some(5));
+    assert_eq!(
+        replica.snapshot()?.metadata[&"g".into()].held,
+        primary.snapshot()?.metadata[&"g".into()].held
+    );
+
+    Ok(())
+}
+
+#[cfg(all(test, feature = "memory-storage"))]
+#[test]
+fn replica_is_read_only_until_promoted() -> Result<()> {
+    let replica = ReplicatedStorage::replica(Storage::new());
+
+    assert!(replica.enqueue(&"a".into(), 1.into()).is_err());
+    assert!(replica.dequeue(&"a".into()).is_err());
+    assert!(replica.transaction(&mut |_| Ok(())).is_err());
+    assert!(replica.subscribe().is_err());
+    assert_eq!(replica.length(&"a".into())?, 0);
+
+    replica.promote();
+    replica.enqueue(&"a".into(), 1.into())?;
+    assert_eq!(replica.length(&"a".into())?, 1);
+
+    Ok(())
+}
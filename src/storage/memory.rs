@@ -1,56 +1,151 @@
 use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, RwLock};
 
 use anyhow::Result;
-use structopt::StructOpt;
 
 use crate::errors::*;
-use crate::storage::StorageBackend;
+use crate::storage::{
+    oldest_index, template_config, End, Enqueued, Envelope, Headers, Message, Metadata, Notifier,
+    Queue, QueueConfig, QueueInfo, Snapshot, StorageBackend, Templates, Transaction, Write,
+};
 use crate::types::*;
 
-#[derive(Debug, Clone, StructOpt)]
-pub struct StorageOptions {}
+#[cfg(test)]
+use crate::storage::values;
+#[cfg(test)]
+use std::{thread, time::Duration};
 
 #[derive(Debug, Clone)]
 pub struct MemoryStorage {
     map: Arc<RwLock<BTreeMap<Identifier, Item>>>,
+    /// Only locked while `map` is, or on its own.
+    templates: Arc<RwLock<Templates>>,
+    /// Only changed while `map` is locked for writing, so that messages are
+    /// stored in the order of their ids.
+    next_id: Arc<AtomicU64>,
+    notifier: Arc<Notifier>,
 }
 
 #[derive(Debug)]
 pub struct Item {
     bounds: (usize, usize),
-    data: Vec<Value>,
+    data: Vec<Message>,
+    meta: Metadata,
 }
 
 impl Default for Item {
     fn default() -> Self {
-        Self {
-            bounds: (0, 0),
-            data: Default::default(),
-        }
+        Item::from(vec![])
     }
 }
 
 impl Item {
+    /// Adds a message to `side`, dropping the oldest messages or failing if
+    /// the queue is full, as its settings say.
     #[inline(always)]
-    fn enqueue(&mut self, v: Value) {
+    fn push(&mut self, id: &Identifier, message: Message, side: End) -> Result<()> {
+        self.meta.config.check_type(&message.value)?;
+        let dropped = self.meta.config.make_room(id, self.length())?;
+
+        for _ in 0..dropped {
+            if let Some(index) = oldest_index(self.values()) {
+                self.remove(index);
+                self.meta.record_drop();
+            }
+        }
+
+        self.compact();
+
         let (start, end) = self.bounds;
-        self.bounds = (start, end + 1);
+        self.meta.record_enqueue(&message);
 
-        self.data.push(v);
+        match side {
+            End::Back => {
+                self.bounds = (start, end + 1);
+                self.data.push(message);
+            }
+            // The slot of the last dequeued message is reused if there is one.
+            End::Front if start > 0 => {
+                self.bounds = (start - 1, end);
+                self.data[start - 1] = message;
+            }
+            End::Front => {
+                self.bounds = (0, end + 1);
+                self.data.insert(0, message);
+            }
+        }
+
+        Ok(())
     }
 
+    /// Removes the message at `index` from the head. Removing the head only
+    /// moves the bounds.
     #[inline(always)]
-    fn dequeue(&mut self) -> Option<&mut Value> {
+    fn remove(&mut self, index: usize) -> Message {
         let (start, end) = self.bounds;
 
-        self.bounds = (start + 1, end);
-        self.data.get_mut(start)
+        if index == 0 {
+            self.bounds = (start + 1, end);
+            self.data[start].clone()
+        } else {
+            self.bounds = (start, end - 1);
+            self.data.remove(start + index)
+        }
     }
 
+    /// Frees the messages before the head of the queue once they take up at
+    /// least half of it, so a queue whose length is bounded also stays
+    /// bounded in memory.
     #[inline(always)]
-    fn peek(&self) -> Option<&Value> {
-        self.data.get(self.bounds.0)
+    fn compact(&mut self) {
+        let (start, end) = self.bounds;
+
+        if start > 0 && start * 2 >= self.data.len() {
+            self.data.drain(..start);
+            self.bounds = (0, end - start);
+        }
+    }
+
+    /// Takes the next message to deliver from `side`. That's the one at
+    /// `side` unless its group is held, in which case a message is removed
+    /// from the middle.
+    #[inline(always)]
+    fn take(&mut self, side: End) -> Option<Message> {
+        let index = self.meta.next_index(self.values(), side)?;
+        let message = self.remove(index);
+
+        self.meta.record_delivery(&message);
+        Some(message)
+    }
+
+    #[inline(always)]
+    fn purge(&mut self) -> usize {
+        let purged = self.length();
+
+        self.bounds = (0, 0);
+        self.data.clear();
+        self.meta.record_purge();
+
+        purged
+    }
+
+    #[inline(always)]
+    fn peek(&self, side: End) -> Option<&Message> {
+        let values = self.values();
+        self.meta
+            .next_index(values, side)
+            .map(|index| &values[index])
+    }
+
+    #[inline(always)]
+    fn range(&self, start: usize, count: usize) -> &[Message] {
+        let values = self.values();
+        let start = start.min(values.len());
+        let end = start.saturating_add(count).min(values.len());
+
+        &values[start..end]
     }
 
     #[inline(always)]
@@ -58,20 +153,108 @@ impl Item {
         let (start, end) = self.bounds;
         end - start
     }
+
+    #[inline(always)]
+    fn values(&self) -> &[Message] {
+        let (start, end) = self.bounds;
+        &self.data[start..end]
+    }
+}
+
+impl Item {
+    fn with_metadata(data: Vec<Message>, meta: Metadata) -> Self {
+        Self {
+            bounds: (0, data.len()),
+            data,
+            meta,
+        }
+    }
+}
+
+impl From<Vec<Message>> for Item {
+    fn from(data: Vec<Message>) -> Self {
+        let meta = Metadata::new(data.len());
+        Self::with_metadata(data, meta)
+    }
+}
+
+#[cfg(test)]
+fn message(id: u64) -> Message {
+    Message {
+        enqueued_at: 0,
+        ..Message::new(id, Value::Integer(id as i64), Headers::new())
+    }
 }
 
 #[test]
 fn enqueued_item_is_dequeued_correctly() {
     let mut item = Item::default();
-    item.enqueue(Value::Integer(1));
-    assert_eq!(item.dequeue(), Some(&mut Value::Integer(1)));
+    item.push(&"a".into(), message(1), End::Back).unwrap();
+    assert_eq!(item.take(End::Front), Some(message(1)));
+}
+
+#[test]
+fn range_of_item_skips_dequeued_values() {
+    let mut item = Item::from(vec![message(1), message(2), message(3)]);
+    item.take(End::Front);
+
+    assert_eq!(item.range(0, 1), &[message(2)]);
+    assert_eq!(item.range(1, 10), &[message(3)]);
+    assert!(item.range(5, usize::MAX).is_empty());
+}
+
+#[test]
+fn dequeueing_empty_item_keeps_length() {
+    let mut item = Item::default();
+    assert_eq!(item.take(End::Front), None);
+    assert_eq!(item.length(), 0);
 }
 
 impl MemoryStorage {
+    /// An empty queue named `id`, with the settings of its template.
+    fn create(&self, id: &Identifier) -> Result<Item> {
+        let templates = self
+            .templates
+            .read()
+            .map_err(|_| StorageError::FailedLock)?;
+        let meta = Metadata::configured(template_config(&templates, id));
+
+        Ok(Item::with_metadata(vec![], meta))
+    }
+
+    /// Adds `message` to `side` of the queue `id` of `map`, which must be
+    /// locked.
+    fn push_into(
+        &self,
+        map: &mut BTreeMap<Identifier, Item>,
+        id: &Identifier,
+        message: Message,
+        side: End,
+    ) -> Result<()> {
+        let next_id = message.id + 1;
+
+        match map.get_mut(id) {
+            Some(item) => item.push(id, message, side)?,
+            None => {
+                let mut item = self.create(id)?;
+                item.push(id, message, side)?;
+
+                map.insert(id.clone(), item);
+            }
+        }
+
+        self.next_id.fetch_max(next_id, Ordering::SeqCst);
+
+        Ok(())
+    }
+
     #[tracing::instrument]
     pub fn new() -> Self {
         Self {
             map: Arc::new(RwLock::new(Default::default())),
+            templates: Arc::new(RwLock::new(Default::default())),
+            next_id: Arc::new(AtomicU64::new(1)),
+            notifier: Arc::new(Notifier::default()),
         }
     }
 }
@@ -79,48 +262,621 @@ impl MemoryStorage {
 #[async_trait::async_trait]
 impl StorageBackend for MemoryStorage {
     #[tracing::instrument]
-    fn enqueue(&self, id: &Identifier, value: Value) -> Result<()> {
+    fn enqueue_message(
+        &self,
+        id: &Identifier,
+        value: Value,
+        envelope: Envelope,
+    ) -> Result<Enqueued> {
         let mut map = self.map.write().map_err(|_| StorageError::FailedLock)?;
 
-        match map.get_mut(&id) {
-            Some(v) => v.enqueue(value),
-            None => {
-                let mut item = Item::default();
-                item.enqueue(value);
+        let duplicate = map
+            .get(id)
+            .and_then(|item| item.meta.duplicate_of(envelope.dedup_id.as_deref()));
 
-                map.insert(id.clone(), item);
-            }
+        if let Some(original) = duplicate {
+            return Ok(Enqueued::Duplicate(original));
         }
+
+        let message = envelope.into_message(self.next_id.load(Ordering::SeqCst), value);
+        self.push_into(&mut map, id, message.clone(), End::Back)?;
+        self.notifier.notify();
+
+        Ok(Enqueued::Message(message))
+    }
+
+    #[tracing::instrument]
+    fn push(&self, id: &Identifier, message: Message) -> Result<()> {
+        let mut map = self.map.write().map_err(|_| StorageError::FailedLock)?;
+
+        self.push_into(&mut map, id, message, End::Back)?;
+        self.notifier.notify();
+
         Ok(())
     }
 
     #[tracing::instrument]
-    fn dequeue(&self, id: &Identifier) -> Result<Value> {
+    fn enqueue_front(&self, id: &Identifier, value: Value, headers: Headers) -> Result<Message> {
         let mut map = self.map.write().map_err(|_| StorageError::FailedLock)?;
 
-        match map.get_mut(&id) {
-            Some(q) => match q.dequeue() {
-                Some(v) => Ok(v.clone()),
-                None => Ok(Value::Null),
-            },
-            None => Ok(Value::Null),
+        let message = Message::new(self.next_id.load(Ordering::SeqCst), value, headers);
+        self.push_into(&mut map, id, message.clone(), End::Front)?;
+        self.notifier.notify();
+
+        Ok(message)
+    }
+
+    #[tracing::instrument]
+    fn push_front(&self, id: &Identifier, message: Message) -> Result<()> {
+        let mut map = self.map.write().map_err(|_| StorageError::FailedLock)?;
+
+        self.push_into(&mut map, id, message, End::Front)?;
+        self.notifier.notify();
+
+        Ok(())
+    }
+
+    #[tracing::instrument]
+    fn dequeue(&self, id: &Identifier) -> Result<Option<Message>> {
+        let mut map = self.map.write().map_err(|_| StorageError::FailedLock)?;
+
+        let message = map.get_mut(id).and_then(|q| q.take(q.meta.head()));
+
+        if message.is_some() {
+            self.notifier.notify();
+        }
+
+        Ok(message.map(Message::delivered))
+    }
+
+    #[tracing::instrument]
+    fn pop_back(&self, id: &Identifier) -> Result<Option<Message>> {
+        let mut map = self.map.write().map_err(|_| StorageError::FailedLock)?;
+
+        let message = map.get_mut(id).and_then(|q| q.take(End::Back));
+
+        if message.is_some() {
+            self.notifier.notify();
         }
+
+        Ok(message.map(Message::delivered))
     }
 
     #[tracing::instrument]
     fn length(&self, id: &Identifier) -> Result<usize> {
         let map = self.map.read().map_err(|_| StorageError::FailedLock)?;
 
-        Ok(map.get(&id).map(|x| x.length()).unwrap_or(0))
+        Ok(map.get(id).map(|x| x.length()).unwrap_or(0))
+    }
+
+    #[tracing::instrument]
+    fn peek(&self, id: &Identifier) -> Result<Option<Message>> {
+        let map = self.map.read().map_err(|_| StorageError::FailedLock)?;
+
+        Ok(map.get(id).and_then(|x| x.peek(x.meta.head())).cloned())
     }
 
     #[tracing::instrument]
-    fn peek(&self, id: &Identifier) -> Result<Value> {
+    fn peek_back(&self, id: &Identifier) -> Result<Option<Message>> {
         let map = self.map.read().map_err(|_| StorageError::FailedLock)?;
 
-        match map.get(&id).and_then(|x| x.peek()) {
-            Some(v) => Ok(v.clone()),
-            None => Ok(Value::Null),
+        Ok(map.get(id).and_then(|x| x.peek(End::Back)).cloned())
+    }
+
+    #[tracing::instrument]
+    fn range(&self, id: &Identifier, start: usize, count: usize) -> Result<Vec<Message>> {
+        let map = self.map.read().map_err(|_| StorageError::FailedLock)?;
+
+        Ok(map
+            .get(id)
+            .map(|x| x.range(start, count).to_vec())
+            .unwrap_or_default())
+    }
+
+    #[tracing::instrument]
+    fn purge(&self, id: &Identifier) -> Result<usize> {
+        let mut map = self.map.write().map_err(|_| StorageError::FailedLock)?;
+
+        match map.get_mut(id) {
+            Some(item) => {
+                let purged = item.purge();
+                self.notifier.notify();
+                Ok(purged)
+            }
+            None => Ok(0),
+        }
+    }
+
+    #[tracing::instrument]
+    fn delete(&self, id: &Identifier) -> Result<bool> {
+        let mut map = self.map.write().map_err(|_| StorageError::FailedLock)?;
+
+        let existed = map.remove(id).is_some();
+
+        if existed {
+            self.notifier.notify();
+        }
+
+        Ok(existed)
+    }
+
+    #[tracing::instrument]
+    fn rename(&self, src: &Identifier, dst: &Identifier) -> Result<()> {
+        let mut map = self.map.write().map_err(|_| StorageError::FailedLock)?;
+
+        match map.remove(src) {
+            Some(item) => {
+                map.insert(dst.clone(), item);
+                self.notifier.notify();
+                Ok(())
+            }
+            None => Err(StorageError::QueueNotFound(src.to_string()).into()),
         }
     }
+
+    #[tracing::instrument]
+    fn copy(&self, src: &Identifier, dst: &Identifier) -> Result<()> {
+        let mut map = self.map.write().map_err(|_| StorageError::FailedLock)?;
+
+        match map.get(src) {
+            Some(item) => {
+                let copy = Item::with_metadata(item.values().to_vec(), item.meta.copied());
+                map.insert(dst.clone(), copy);
+                self.notifier.notify();
+                Ok(())
+            }
+            None => Err(StorageError::QueueNotFound(src.to_string()).into()),
+        }
+    }
+
+    #[tracing::instrument]
+    fn scan(&self, after: Option<&Identifier>, count: usize) -> Result<Vec<Identifier>> {
+        let map = self.map.read().map_err(|_| StorageError::FailedLock)?;
+
+        let start = match after {
+            Some(after) => Bound::Excluded(after),
+            None => Bound::Unbounded,
+        };
+
+        Ok(map
+            .range((start, Bound::Unbounded))
+            .take(count)
+            .map(|(id, _)| id.clone())
+            .collect())
+    }
+
+    #[tracing::instrument]
+    fn info(&self, id: &Identifier) -> Result<Option<QueueInfo>> {
+        let map = self.map.read().map_err(|_| StorageError::FailedLock)?;
+
+        Ok(map.get(id).map(|item| item.meta.info(item.values())))
+    }
+
+    #[tracing::instrument]
+    fn config(&self, id: &Identifier) -> Result<QueueConfig> {
+        let map = self.map.read().map_err(|_| StorageError::FailedLock)?;
+
+        match map.get(id) {
+            Some(item) => Ok(item.meta.config.clone()),
+            None => Ok(self.create(id)?.meta.config),
+        }
+    }
+
+    #[tracing::instrument]
+    fn templates(&self) -> Result<Templates> {
+        let templates = self
+            .templates
+            .read()
+            .map_err(|_| StorageError::FailedLock)?;
+
+        Ok(templates.clone())
+    }
+
+    #[tracing::instrument(skip(f))]
+    fn update_template(&self, pattern: &str, f: &mut dyn FnMut(&mut QueueConfig)) -> Result<()> {
+        let mut templates = self
+            .templates
+            .write()
+            .map_err(|_| StorageError::FailedLock)?;
+
+        f(templates.entry(pattern.to_string()).or_default());
+
+        Ok(())
+    }
+
+    #[tracing::instrument]
+    fn release(&self, id: &Identifier, group: &str) -> Result<bool> {
+        let mut map = self.map.write().map_err(|_| StorageError::FailedLock)?;
+
+        let released = map.get_mut(id).is_some_and(|item| item.meta.release(group));
+
+        if released {
+            self.notifier.notify();
+        }
+
+        Ok(released)
+    }
+
+    #[tracing::instrument]
+    fn snapshot(&self) -> Result<Snapshot> {
+        let map = self.map.read().map_err(|_| StorageError::FailedLock)?;
+
+        Ok(Snapshot {
+            queues: map
+                .iter()
+                .map(|(id, item)| (id.clone(), item.values().to_vec()))
+                .collect(),
+            metadata: map
+                .iter()
+                .map(|(id, item)| (id.clone(), item.meta.clone()))
+                .collect(),
+            templates: self.templates()?,
+            next_id: self.next_id.load(Ordering::SeqCst),
+        })
+    }
+
+    #[tracing::instrument]
+    fn restore(&self, snapshot: Snapshot) -> Result<()> {
+        let mut map = self.map.write().map_err(|_| StorageError::FailedLock)?;
+        let mut templates = self
+            .templates
+            .write()
+            .map_err(|_| StorageError::FailedLock)?;
+
+        *templates = snapshot.templates;
+        self.next_id.store(snapshot.next_id, Ordering::SeqCst);
+        let mut metadata = snapshot.metadata;
+
+        *map = snapshot
+            .queues
+            .into_iter()
+            .map(|(id, values)| match metadata.remove(&id) {
+                Some(meta) => (id, Item::with_metadata(values, meta)),
+                None => (id, values.into()),
+            })
+            .collect();
+        self.notifier.notify();
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(f))]
+    fn transaction(&self, f: &mut dyn FnMut(&Transaction) -> Result<()>) -> Result<Vec<Write>> {
+        let mut map = self.map.write().map_err(|_| StorageError::FailedLock)?;
+
+        let (writes, changes, next_id) = {
+            let source = |id: &Identifier| -> Result<Option<Queue>> {
+                Ok(map.get(id).map(|item| Queue {
+                    values: item.values().iter().cloned().collect(),
+                    meta: item.meta.clone(),
+                }))
+            };
+            let transaction = Transaction::new(
+                &source,
+                self.templates()?,
+                self.next_id.load(Ordering::SeqCst),
+            );
+
+            f(&transaction)?;
+            transaction.commit()?
+        };
+
+        // Nobody needs waking when nothing changed, as when a move finds its
+        // source empty.
+        if changes.is_empty() {
+            return Ok(writes);
+        }
+
+        for (id, queue) in changes {
+            match queue {
+                Some(queue) => map.insert(id, Item::with_metadata(queue.values.into(), queue.meta)),
+                None => map.remove(&id),
+            };
+        }
+
+        self.next_id.store(next_id, Ordering::SeqCst);
+        self.notifier.notify();
+
+        Ok(writes)
+    }
+
+    fn notifier(&self) -> &Notifier {
+        &self.notifier
+    }
+}
+
+#[test]
+fn restoring_a_snapshot_replaces_all_queues() -> Result<()> {
+    let storage = MemoryStorage::new();
+    storage.enqueue(&"a".into(), 1.into())?;
+    storage.enqueue(&"a".into(), 2.into())?;
+    storage.dequeue(&"a".into())?;
+
+    let snapshot = storage.snapshot()?;
+    let queues = snapshot.queues.clone();
+    assert_eq!(queues.len(), 1);
+    assert_eq!(values(queues[0].1.clone()), vec![2.into()]);
+
+    let other = MemoryStorage::new();
+    other.enqueue(&"b".into(), 3.into())?;
+    other.restore(snapshot)?;
+
+    assert_eq!(other.length(&"b".into())?, 0);
+    assert_eq!(other.dequeue(&"a".into())?.unwrap().value, 2.into());
+    assert_eq!(other.enqueue(&"a".into(), 3.into())?.id(), 3);
+
+    Ok(())
+}
+
+#[test]
+fn failed_transaction_writes_nothing() -> Result<()> {
+    let storage = MemoryStorage::new();
+    storage.enqueue(&"a".into(), 1.into())?;
+
+    let writes = storage.transaction(&mut |tx| {
+        let message = tx.dequeue(&"a".into())?.unwrap();
+        tx.enqueue(&"b".into(), message.value)?;
+        Ok(())
+    })?;
+
+    assert_eq!(writes.len(), 2);
+    assert_eq!(storage.length(&"a".into())?, 0);
+    assert_eq!(storage.peek(&"b".into())?.unwrap().value, 1.into());
+    assert_eq!(storage.enqueue(&"c".into(), 1.into())?.id(), 3);
+
+    let failed = storage.transaction(&mut |tx| {
+        tx.dequeue(&"b".into())?;
+        anyhow::bail!("failed")
+    });
+
+    assert!(failed.is_err());
+    assert_eq!(storage.peek(&"b".into())?.unwrap().value, 1.into());
+
+    Ok(())
+}
+
+#[test]
+fn queues_are_purged_renamed_copied_and_deleted() -> Result<()> {
+    let storage = MemoryStorage::new();
+    storage.enqueue(&"a".into(), 1.into())?;
+    storage.enqueue(&"a".into(), 2.into())?;
+    storage.dequeue(&"a".into())?;
+
+    storage.copy(&"a".into(), &"b".into())?;
+    storage.rename(&"a".into(), &"c".into())?;
+    assert!(storage.rename(&"a".into(), &"d".into()).is_err());
+    assert_eq!(values(storage.range(&"b".into(), 0, 10)?), vec![2.into()]);
+    assert_eq!(values(storage.range(&"c".into(), 0, 10)?), vec![2.into()]);
+
+    assert_eq!(storage.purge(&"b".into())?, 1);
+    assert!(storage.delete(&"c".into())?);
+    assert!(!storage.delete(&"c".into())?);
+
+    let queues = storage.snapshot()?.queues;
+    assert_eq!(queues, vec![("b".into(), vec![])]);
+
+    let info = storage.info(&"b".into())?.unwrap();
+    assert_eq!((info.length, info.enqueued, info.dequeued), (0, 1, 0));
+    assert_eq!(info.oldest_age, None);
+    assert_eq!(storage.info(&"c".into())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn scanning_lists_queues_in_pages() -> Result<()> {
+    let storage = MemoryStorage::new();
+
+    for id in ["c", "a", "d", "b"] {
+        storage.enqueue(&id.into(), 1.into())?;
+    }
+
+    let first = storage.scan(None, 3)?;
+    assert_eq!(first, vec!["a".into(), "b".into(), "c".into()]);
+    assert_eq!(storage.scan(first.last(), 3)?, vec!["d".into()]);
+    assert!(storage.scan(Some(&"d".into()), 3)?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn full_queues_reject_or_drop_values() -> Result<()> {
+    let storage = MemoryStorage::new();
+    storage.update_config(&"a".into(), &mut |config| config.max_length = Some(2))?;
+
+    storage.enqueue(&"a".into(), 1.into())?;
+    storage.enqueue(&"a".into(), 2.into())?;
+    let full = storage.enqueue(&"a".into(), 3.into()).unwrap_err();
+    assert_eq!(
+        full.downcast_ref(),
+        Some(&StorageError::QueueFull("a".into()))
+    );
+
+    storage.update_config(&"a".into(), &mut |config| {
+        config.overflow = Overflow::DropOldest
+    })?;
+
+    for value in 3..10 {
+        storage.enqueue(&"a".into(), value.into())?;
+    }
+
+    assert_eq!(
+        values(storage.range(&"a".into(), 0, 10)?),
+        vec![8.into(), 9.into()]
+    );
+    assert!(storage.map.read().unwrap()[&"a".into()].data.len() <= 4);
+
+    // A value pushed to the front drops the oldest value, not the newest.
+    storage.enqueue_front(&"a".into(), 7.into(), Headers::new())?;
+    assert_eq!(
+        values(storage.range(&"a".into(), 0, 10)?),
+        vec![7.into(), 9.into()]
+    );
+
+    // Purging and copying keep the settings of a queue.
+    storage.purge(&"a".into())?;
+    storage.copy(&"a".into(), &"b".into())?;
+    assert_eq!(storage.config(&"b".into())?.max_length, Some(2));
+
+    Ok(())
+}
+
+#[test]
+fn new_queues_use_their_template() -> Result<()> {
+    let storage = MemoryStorage::new();
+    storage.enqueue(&"orders_old".into(), 1.into())?;
+    storage.update_template("orders_*", &mut |config| config.max_length = Some(1))?;
+
+    storage.enqueue(&"orders_old".into(), 2.into())?;
+    storage.enqueue(&"orders_new".into(), 1.into())?;
+    assert!(storage.enqueue(&"orders_new".into(), 2.into()).is_err());
+    assert_eq!(storage.config(&"orders_other".into())?.max_length, Some(1));
+
+    let other = MemoryStorage::new();
+    other.restore(storage.snapshot()?)?;
+    assert_eq!(other.templates()?, storage.templates()?);
+
+    Ok(())
+}
+
+#[test]
+fn messages_keep_their_ids_and_headers() -> Result<()> {
+    let storage = MemoryStorage::new();
+
+    let mut headers = Headers::new();
+    headers.insert("trace".into(), "abc".to_string().into());
+    let first = storage
+        .enqueue_message(
+            &"a".into(),
+            1.into(),
+            Envelope {
+                headers,
+                ..Envelope::default()
+            },
+        )?
+        .message()
+        .unwrap();
+    let second = storage.enqueue(&"b".into(), 2.into())?.message().unwrap();
+    assert!(second.id > first.id);
+
+    storage.move_value(&"a".into(), &"b".into())?;
+    assert_eq!(storage.peek(&"b".into())?, Some(second));
+
+    let moved = storage.range(&"b".into(), 1, 1)?.remove(0);
+    assert_eq!((moved.id, moved.deliveries), (first.id, 1));
+    assert_eq!(moved.headers, first.headers);
+
+    storage.dequeue(&"b".into())?;
+    assert_eq!(storage.dequeue(&"b".into())?.unwrap().deliveries, 2);
+    assert_eq!(storage.dequeue(&"b".into())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn duplicate_enqueues_are_ignored_within_the_window() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let dedup = |id: &str| Envelope {
+        dedup_id: Some(id.to_string()),
+        ..Envelope::default()
+    };
+
+    let first = storage.enqueue_message(&"a".into(), 1.into(), dedup("x"))?;
+    let retried = storage.enqueue_message(&"a".into(), 1.into(), dedup("x"))?;
+    storage.enqueue_message(&"b".into(), 1.into(), dedup("x"))?;
+
+    assert_eq!(retried, Enqueued::Duplicate(first.id()));
+    assert_eq!(storage.length(&"a".into())?, 1);
+    assert_eq!(storage.length(&"b".into())?, 1);
+
+    // Dequeueing the message doesn't forget its dedup id.
+    storage.dequeue(&"a".into())?;
+    let retried = storage.enqueue_message(&"a".into(), 1.into(), dedup("x"))?;
+    assert_eq!(retried.id(), first.id());
+
+    storage.update_config(&"a".into(), &mut |config| config.dedup_window = 0)?;
+    let enqueued = storage.enqueue_message(&"a".into(), 1.into(), dedup("x"))?;
+    assert!(enqueued.id() > first.id());
+
+    Ok(())
+}
+
+#[test]
+fn groups_deliver_one_message_at_a_time() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let group = |group: &str| Envelope {
+        group: Some(group.to_string()),
+        ..Envelope::default()
+    };
+
+    storage.enqueue_message(&"a".into(), 1.into(), group("x"))?;
+    storage.enqueue_message(&"a".into(), 2.into(), group("x"))?;
+    storage.enqueue_message(&"a".into(), 3.into(), group("y"))?;
+    storage.enqueue(&"a".into(), 4.into())?;
+
+    let next = |storage: &MemoryStorage| -> Result<Option<Value>> {
+        Ok(storage.dequeue(&"a".into())?.map(|message| message.value))
+    };
+
+    assert_eq!(next(&storage)?, Some(1.into()));
+    assert_eq!(storage.peek(&"a".into())?.unwrap().value, 3.into());
+    assert_eq!(next(&storage)?, Some(3.into()));
+    assert_eq!(next(&storage)?, Some(4.into()));
+    assert_eq!(next(&storage)?, None);
+    assert_eq!(storage.length(&"a".into())?, 1);
+
+    assert!(storage.release(&"a".into(), "x")?);
+    assert!(!storage.release(&"a".into(), "x")?);
+    assert_eq!(next(&storage)?, Some(2.into()));
+
+    let info = storage.info(&"a".into())?.unwrap();
+    assert_eq!((info.length, info.dequeued), (0, 4));
+
+    Ok(())
+}
+
+#[test]
+fn queues_are_used_from_both_ends() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let headers = Headers::from([("attempt".to_string(), 2.into())]);
+    storage.enqueue(&"a".into(), 2.into())?;
+    storage.enqueue_front(&"a".into(), 1.into(), headers.clone())?;
+    storage.enqueue(&"a".into(), 3.into())?;
+
+    assert_eq!(
+        values(storage.range(&"a".into(), 0, 10)?),
+        vec![1.into(), 2.into(), 3.into()]
+    );
+    assert_eq!(storage.peek(&"a".into())?.unwrap().headers, headers);
+    assert_eq!(storage.peek_back(&"a".into())?.unwrap().value, 3.into());
+
+    // The value pushed to the front is the newest, so the age of the queue
+    // is that of the value behind it.
+    thread::sleep(Duration::from_millis(20));
+    storage.enqueue_front(&"a".into(), 0.into(), Headers::new())?;
+    assert!(storage.info(&"a".into())?.unwrap().oldest_age >= Some(20));
+    storage.dequeue(&"a".into())?;
+
+    assert_eq!(storage.pop_back(&"a".into())?.unwrap().value, 3.into());
+    assert_eq!(storage.dequeue(&"a".into())?.unwrap().value, 1.into());
+
+    // A stack delivers its newest value first, and drops its oldest when
+    // full.
+    storage.update_config(&"b".into(), &mut |config| {
+        config.mode = Mode::Stack;
+        config.max_length = Some(2);
+        config.overflow = Overflow::DropOldest;
+    })?;
+
+    for value in 1..4 {
+        storage.enqueue(&"b".into(), value.into())?;
+    }
+
+    assert_eq!(storage.peek(&"b".into())?.unwrap().value, 3.into());
+    assert_eq!(storage.dequeue(&"b".into())?.unwrap().value, 3.into());
+    assert_eq!(storage.dequeue(&"b".into())?.unwrap().value, 2.into());
+    assert_eq!(storage.dequeue(&"b".into())?, None);
+    assert_eq!(storage.info(&"b".into())?.unwrap().mode, Mode::Stack);
+
+    Ok(())
 }
@@ -1,12 +1,17 @@
-use anyhow::Result;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::errors::*;
+use crate::glob;
 use crate::types::*;
 
 #[cfg(feature = "memory-storage")]
 mod memory;
 #[cfg(feature = "memory-storage")]
-pub use self::memory::StorageOptions;
-#[cfg(feature = "memory-storage")]
 pub use memory::MemoryStorage as Storage;
 
 #[cfg(feature = "rocksdb-storage")]
@@ -16,9 +21,718 @@ pub use self::rocksdb::RocksDBStorage as Storage;
 #[cfg(feature = "rocksdb-storage")]
 pub use self::rocksdb::StorageOptions;
 
+mod transaction;
+pub(crate) use self::transaction::Queue;
+pub use self::transaction::{Transaction, Write};
+
+/// A point-in-time copy of every queue in a storage backend, in queue order.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub queues: Vec<(Identifier, Vec<Message>)>,
+    pub metadata: BTreeMap<Identifier, Metadata>,
+    pub templates: Templates,
+    /// The id the next enqueued message gets.
+    pub next_id: u64,
+}
+
+/// Headers sent along with a message, such as a trace id.
+pub type Headers = BTreeMap<String, Value>;
+
+/// A value in a queue, along with what the server records about it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Message {
+    /// Assigned on enqueue, increasing across every queue of the storage.
+    pub id: u64,
+    pub value: Value,
+    pub headers: Headers,
+    /// Milliseconds since the Unix epoch.
+    pub enqueued_at: u64,
+    /// How many times the message has been dequeued, counting moves.
+    pub deliveries: u64,
+    /// Set by the producer, so that retried enqueues of the same message
+    /// are ignored.
+    pub dedup_id: Option<String>,
+    /// Messages of the same group are delivered in order, one at a time.
+    pub group: Option<String>,
+}
+
+impl Message {
+    /// A message enqueued now, never delivered yet.
+    pub fn new(id: u64, value: Value, headers: Headers) -> Self {
+        Self {
+            id,
+            value,
+            headers,
+            enqueued_at: now(),
+            deliveries: 0,
+            dedup_id: None,
+            group: None,
+        }
+    }
+
+    /// The message as it is handed out by a dequeue.
+    pub fn delivered(mut self) -> Self {
+        self.deliveries += 1;
+        self
+    }
+}
+
+/// What a producer sends along with a value when enqueueing it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Envelope {
+    pub headers: Headers,
+    pub dedup_id: Option<String>,
+    pub group: Option<String>,
+}
+
+impl Envelope {
+    /// A new message with `id` and `value`, sent in this envelope.
+    pub fn into_message(self, id: u64, value: Value) -> Message {
+        Message {
+            dedup_id: self.dedup_id,
+            group: self.group,
+            ..Message::new(id, value, self.headers)
+        }
+    }
+}
+
+/// What an enqueue did.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Enqueued {
+    Message(Message),
+    /// Nothing was enqueued, since the dedup id was seen recently. Holds the
+    /// id of the message that was enqueued with it.
+    Duplicate(u64),
+}
+
+impl Enqueued {
+    /// The id of the message, whether it was just enqueued or not.
+    pub fn id(&self) -> u64 {
+        match self {
+            Enqueued::Message(message) => message.id,
+            Enqueued::Duplicate(id) => *id,
+        }
+    }
+
+    /// The message that was enqueued, unless it was a duplicate.
+    pub fn message(self) -> Option<Message> {
+        match self {
+            Enqueued::Message(message) => Some(message),
+            Enqueued::Duplicate(_) => None,
+        }
+    }
+}
+
+/// Milliseconds since the Unix epoch.
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|t| t.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// An end of a queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum End {
+    Front,
+    Back,
+}
+
+impl End {
+    pub fn opposite(self) -> Self {
+        match self {
+            End::Front => End::Back,
+            End::Back => End::Front,
+        }
+    }
+}
+
+/// How long an enqueue into a full queue with the `block` policy waits for
+/// room if it doesn't say, before failing with `QueueFull`.
+pub(crate) const BLOCK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Wakes the commands that wait for queues to change. Backends count every
+/// write that may let one go on, such as a dequeue making room or an enqueue
+/// giving a move a value, and waiters sleep until the count passes the one
+/// they saw before trying.
+#[derive(Debug, Default)]
+pub struct Notifier {
+    changes: Mutex<u64>,
+    changed: Condvar,
+}
+
+impl Notifier {
+    /// How many changes there have been so far.
+    pub fn changes(&self) -> Result<u64> {
+        Ok(*self.changes.lock().map_err(|_| StorageError::FailedLock)?)
+    }
+
+    pub fn notify(&self) {
+        // A poisoned lock only means a waiter panicked, so the count is
+        // still bumped for the others.
+        let mut changes = self.changes.lock().unwrap_or_else(|e| e.into_inner());
+        *changes += 1;
+        self.changed.notify_all();
+    }
+
+    /// Sleeps until there have been more than `seen` changes, or `deadline`
+    /// passes. Returns whether there have.
+    pub fn wait(&self, seen: u64, deadline: Instant) -> Result<bool> {
+        let mut changes = self.changes.lock().map_err(|_| StorageError::FailedLock)?;
+
+        while *changes == seen {
+            let now = Instant::now();
+
+            if now >= deadline {
+                return Ok(false);
+            }
+
+            changes = self
+                .changed
+                .wait_timeout(changes, deadline - now)
+                .map_err(|_| StorageError::FailedLock)?
+                .0;
+        }
+
+        Ok(true)
+    }
+}
+
+/// Settings of a queue, changed with `configure`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueueConfig {
+    /// How many values the queue may hold, or `None` for no limit.
+    pub max_length: Option<usize>,
+    /// What an enqueue does when the queue is full.
+    pub overflow: Overflow,
+    /// For how many seconds an enqueue with the same dedup id as an earlier
+    /// one is ignored.
+    pub dedup_window: u64,
+    /// Whether `dequeue` takes values from the front or the back.
+    pub mode: Mode,
+    /// The type of the values the queue accepts, or `None` for any.
+    pub value_type: Option<ValueType>,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            max_length: None,
+            overflow: Overflow::default(),
+            dedup_window: 5 * 60,
+            mode: Mode::default(),
+            value_type: None,
+        }
+    }
+}
+
+impl QueueConfig {
+    /// Fails unless the queue accepts `value`. Values already in the queue
+    /// aren't checked when its type changes.
+    pub fn check_type(&self, value: &Value) -> Result<()> {
+        match self.value_type {
+            Some(expected) if value.value_type() != expected => {
+                bail!(DataError::UnexpectedValue {
+                    expected: format!("a value of type {}", expected),
+                    got: Literal(value).to_string(),
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// How many values of `id` must be dropped before adding one more to it
+    /// while it holds `length` values. The oldest are dropped, wherever they
+    /// are in the queue (see `oldest_index`). Fails if the queue is full and
+    /// its policy doesn't drop values.
+    pub fn make_room(&self, id: &Identifier, length: usize) -> Result<usize> {
+        match self.max_length {
+            Some(max) if length >= max => match self.overflow {
+                Overflow::DropOldest => Ok(length + 1 - max),
+                Overflow::Reject | Overflow::Block => {
+                    bail!(StorageError::QueueFull(id.to_string()))
+                }
+            },
+            _ => Ok(0),
+        }
+    }
+}
+
+/// The position of the message of `messages` that was enqueued first, the
+/// one a full queue with the `drop_oldest` policy drops. Messages enqueued
+/// in the same millisecond are told apart by their ids.
+pub(crate) fn oldest_index<'a, I>(messages: I) -> Option<usize>
+where
+    I: IntoIterator<Item = &'a Message>,
+{
+    messages
+        .into_iter()
+        .enumerate()
+        .min_by_key(|(_, message)| (message.enqueued_at, message.id))
+        .map(|(index, _)| index)
+}
+
+/// The settings new queues are created with, by the glob pattern their
+/// names must match.
+pub type Templates = BTreeMap<String, QueueConfig>;
+
+/// The settings a queue named `id` is created with: those of the template
+/// with the longest pattern that matches it, or the defaults.
+pub fn template_config(templates: &Templates, id: &Identifier) -> QueueConfig {
+    templates
+        .iter()
+        .filter(|(pattern, _)| glob::matches(pattern, &id.0))
+        .max_by_key(|(pattern, _)| pattern.len())
+        .map(|(_, config)| config.clone())
+        .unwrap_or_default()
+}
+
+/// The dedup ids enqueued into a queue within its dedup window.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RecentIds {
+    /// The id of the message enqueued with each dedup id, and when.
+    ids: HashMap<String, (u64, u64)>,
+    /// The dedup ids in the order they were recorded, so that the expired
+    /// ones are found without looking at every id.
+    order: VecDeque<String>,
+}
+
+impl RecentIds {
+    /// The id of the message enqueued with `dedup_id`, unless that was at
+    /// least `window` milliseconds before `now`.
+    pub fn get(&self, dedup_id: &str, window: u64, now: u64) -> Option<u64> {
+        self.ids
+            .get(dedup_id)
+            .filter(|(_, at)| at.saturating_add(window) > now)
+            .map(|(id, _)| *id)
+    }
+
+    /// Records that message `id` was enqueued with `dedup_id` at `at`,
+    /// forgetting the ids that expired by then.
+    pub fn record(&mut self, dedup_id: &str, id: u64, at: u64, window: u64) {
+        while let Some(oldest) = self.order.front() {
+            match self.ids.get(oldest) {
+                Some((_, seen)) if seen.saturating_add(window) > at => break,
+                _ => {
+                    let oldest = self.order.pop_front().unwrap();
+                    self.ids.remove(&oldest);
+                }
+            }
+        }
+
+        if self.get(dedup_id, window, at).is_none() {
+            self.ids.insert(dedup_id.into(), (id, at));
+            self.order.push_back(dedup_id.into());
+        }
+    }
+
+    /// Each dedup id with the id of its message and when it was recorded,
+    /// oldest first. Ids that expired since are included until the next
+    /// `record`.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u64, u64)> {
+        self.order.iter().filter_map(move |dedup_id| {
+            self.ids
+                .get(dedup_id)
+                .map(|&(id, at)| (dedup_id.as_str(), id, at))
+        })
+    }
+}
+
+/// Bookkeeping kept next to the values of each queue, reported by `info`.
+/// Times are in milliseconds since the Unix epoch.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Metadata {
+    pub created_at: u64,
+    pub enqueued: u64,
+    pub dequeued: u64,
+    /// How many values the queue holds, so that a full queue is noticed
+    /// without reading its values.
+    pub length: usize,
+    pub config: QueueConfig,
+    pub recent: RecentIds,
+    /// The groups with a message in flight, which is dequeued but not
+    /// released yet. No other message of these groups is delivered.
+    pub held: BTreeSet<String>,
+}
+
+impl Metadata {
+    /// Metadata for a queue that starts out now with `length` values.
+    pub fn new(length: usize) -> Self {
+        let now = now();
+
+        Self {
+            created_at: now,
+            enqueued: length as u64,
+            dequeued: 0,
+            length,
+            config: QueueConfig::default(),
+            recent: RecentIds::default(),
+            held: BTreeSet::new(),
+        }
+    }
+
+    /// Metadata for an empty queue created now with `config`.
+    pub fn configured(config: QueueConfig) -> Self {
+        Self {
+            config,
+            ..Self::new(0)
+        }
+    }
+
+    /// Metadata for a copy of this queue, created now. The copy keeps the
+    /// settings and recent dedup ids of the queue. Messages in flight belong
+    /// to the original queue, so no group of the copy is held.
+    pub fn copied(&self) -> Self {
+        Self {
+            created_at: now(),
+            enqueued: self.length as u64,
+            dequeued: 0,
+            length: self.length,
+            config: self.config.clone(),
+            recent: self.recent.clone(),
+            held: BTreeSet::new(),
+        }
+    }
+
+    /// The end `dequeue` and `peek` work on, as the mode of the queue says.
+    pub fn head(&self) -> End {
+        match self.config.mode {
+            Mode::Fifo => End::Front,
+            Mode::Stack => End::Back,
+        }
+    }
+
+    /// The position of the next message to deliver from `end`: the nearest
+    /// one to it that isn't in a held group.
+    pub fn next_index<'a, I>(&self, messages: I, end: End) -> Option<usize>
+    where
+        I: IntoIterator<Item = &'a Message>,
+        I::IntoIter: DoubleEndedIterator + ExactSizeIterator,
+    {
+        let deliverable = |message: &Message| match &message.group {
+            Some(group) => !self.held.contains(group),
+            None => true,
+        };
+        let mut messages = messages.into_iter();
+
+        match end {
+            End::Front => messages.position(deliverable),
+            End::Back => messages.rposition(deliverable),
+        }
+    }
+
+    /// The id of the message recently enqueued with `dedup_id`, if any.
+    pub fn duplicate_of(&self, dedup_id: Option<&str>) -> Option<u64> {
+        let window = self.config.dedup_window.saturating_mul(1000);
+
+        dedup_id.and_then(|dedup_id| self.recent.get(dedup_id, window, now()))
+    }
+
+    /// Records `message` being added to the queue.
+    pub fn record_enqueue(&mut self, message: &Message) {
+        self.enqueued += 1;
+        self.length += 1;
+
+        if let Some(dedup_id) = &message.dedup_id {
+            let window = self.config.dedup_window.saturating_mul(1000);
+            self.recent
+                .record(dedup_id, message.id, message.enqueued_at, window);
+        }
+    }
+
+    /// Records the delivery of `message`, holding its group until it is
+    /// released.
+    pub fn record_delivery(&mut self, message: &Message) {
+        self.dequeued += 1;
+        self.length = self.length.saturating_sub(1);
+
+        if let Some(group) = &message.group {
+            self.held.insert(group.clone());
+        }
+    }
+
+    /// Lets the next message of `group` be delivered. Returns whether the
+    /// group was held.
+    pub fn release(&mut self, group: &str) -> bool {
+        self.held.remove(group)
+    }
+
+    /// Records a value dropped to make room for another. Dropped values are
+    /// not counted as dequeued.
+    pub fn record_drop(&mut self) {
+        self.length = self.length.saturating_sub(1);
+    }
+
+    pub fn record_purge(&mut self) {
+        self.length = 0;
+    }
+
+    /// What `info` reports about the queue holding `messages`. The oldest
+    /// message isn't always at the front, as `push_front` adds messages
+    /// there too.
+    pub fn info<'a, I>(&self, messages: I) -> QueueInfo
+    where
+        I: IntoIterator<Item = &'a Message>,
+    {
+        let mut length = 0;
+        let mut oldest: Option<u64> = None;
+
+        for message in messages {
+            length += 1;
+            oldest = Some(oldest.map_or(message.enqueued_at, |at| at.min(message.enqueued_at)));
+        }
+
+        QueueInfo {
+            mode: self.config.mode,
+            value_type: self.config.value_type,
+            length,
+            created_at: self.created_at,
+            enqueued: self.enqueued,
+            dequeued: self.dequeued,
+            oldest_age: oldest.map(|at| now().saturating_sub(at)),
+        }
+    }
+}
+
+/// What `info` reports about a queue.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueueInfo {
+    pub mode: Mode,
+    pub value_type: Option<ValueType>,
+    pub length: usize,
+    /// Milliseconds since the Unix epoch.
+    pub created_at: u64,
+    pub enqueued: u64,
+    pub dequeued: u64,
+    /// Milliseconds since the oldest value in the queue was enqueued.
+    pub oldest_age: Option<u64>,
+}
+
 pub trait StorageBackend {
-    fn enqueue(&self, id: &Identifier, value: Value) -> Result<()>;
-    fn dequeue(&self, id: &Identifier) -> Result<Value>;
+    /// Adds a value to the back of a queue, as a new message with the next
+    /// id. If the queue is full, this either drops its oldest messages or
+    /// fails with `QueueFull`, as its overflow policy says.
+    ///
+    /// Nothing is enqueued if a message with the same dedup id was
+    /// enqueued into the queue within its dedup window.
+    fn enqueue_message(
+        &self,
+        id: &Identifier,
+        value: Value,
+        envelope: Envelope,
+    ) -> Result<Enqueued>;
+    /// Adds a message to the back of a queue as it is, as when replaying a
+    /// write or moving it from another queue. Messages enqueued afterwards
+    /// get greater ids.
+    fn push(&self, id: &Identifier, message: Message) -> Result<()>;
+    /// Adds a value to the front of a queue, as a new message with the next
+    /// id. A full queue with the `drop_oldest` policy drops its oldest
+    /// values to make room, as for an enqueue.
+    ///
+    /// The message has no dedup id or group. It goes ahead of every other,
+    /// so in a group it would be delivered before older messages of the
+    /// group, and dedup ids are for producers retrying appends.
+    fn enqueue_front(&self, id: &Identifier, value: Value, headers: Headers) -> Result<Message>;
+    /// Adds a message to the front of a queue as it is, as when replaying a
+    /// write.
+    fn push_front(&self, id: &Identifier, message: Message) -> Result<()>;
+    /// Removes the next message of a queue, counting its delivery, or
+    /// returns `None` if there is none. The next message is the one at the
+    /// front, or the back for a stack, skipping those whose group has a
+    /// message in flight.
+    fn dequeue(&self, id: &Identifier) -> Result<Option<Message>>;
+    /// Removes the message nearest to the back of a queue, as `dequeue`
+    /// does for a stack.
+    fn pop_back(&self, id: &Identifier) -> Result<Option<Message>>;
     fn length(&self, id: &Identifier) -> Result<usize>;
-    fn peek(&self, id: &Identifier) -> Result<Value>;
+    /// The message the next dequeue would return.
+    fn peek(&self, id: &Identifier) -> Result<Option<Message>>;
+    /// The message the next `pop_back` would return.
+    fn peek_back(&self, id: &Identifier) -> Result<Option<Message>>;
+    /// Up to `count` messages of a queue starting at `start`, without
+    /// dequeueing them.
+    fn range(&self, id: &Identifier, start: usize, count: usize) -> Result<Vec<Message>>;
+    /// Removes every value of a queue, keeping the queue itself. Returns how
+    /// many values were removed.
+    fn purge(&self, id: &Identifier) -> Result<usize>;
+    /// Removes a queue entirely. Returns whether it existed.
+    fn delete(&self, id: &Identifier) -> Result<bool>;
+    /// Moves a whole queue to a new name, replacing any queue already there.
+    fn rename(&self, src: &Identifier, dst: &Identifier) -> Result<()>;
+    /// Copies a whole queue to a new name, replacing any queue already there.
+    fn copy(&self, src: &Identifier, dst: &Identifier) -> Result<()>;
+    /// Up to `count` queue names in order, starting after `after`, or from
+    /// the first queue. Each call only looks at one page, so listing a large
+    /// keyspace doesn't hold up other clients.
+    fn scan(&self, after: Option<&Identifier>, count: usize) -> Result<Vec<Identifier>>;
+    /// Statistics about a queue, or `None` if it doesn't exist.
+    fn info(&self, id: &Identifier) -> Result<Option<QueueInfo>>;
+    /// The settings of a queue, or those it would be created with if it
+    /// doesn't exist.
+    fn config(&self, id: &Identifier) -> Result<QueueConfig>;
+    fn templates(&self) -> Result<Templates>;
+    /// Changes the template for queues matching `pattern` with `f`, starting
+    /// from the defaults if there is none. Only queues created afterwards
+    /// use the new settings.
+    fn update_template(&self, pattern: &str, f: &mut dyn FnMut(&mut QueueConfig)) -> Result<()>;
+    /// Ends the delivery of the message in flight of `group`, letting its
+    /// next message be dequeued. Returns whether the group was held.
+    fn release(&self, id: &Identifier, group: &str) -> Result<bool>;
+    fn snapshot(&self) -> Result<Snapshot>;
+    /// Replaces the whole contents of the storage with `snapshot`.
+    fn restore(&self, snapshot: Snapshot) -> Result<()>;
+    /// Runs `f` isolated from every other client, then applies the writes
+    /// it made all at once. Nothing is written if `f` fails.
+    ///
+    /// Returns the writes that were applied.
+    fn transaction(&self, f: &mut dyn FnMut(&Transaction) -> Result<()>) -> Result<Vec<Write>>;
+    /// Wakes the commands waiting for a queue to change.
+    fn notifier(&self) -> &Notifier;
+
+    /// Enqueues a value without headers, dedup id or group.
+    fn enqueue(&self, id: &Identifier, value: Value) -> Result<Enqueued> {
+        self.enqueue_message(id, value, Envelope::default())
+    }
+
+    /// Atomically dequeues a message from `src` and pushes it into `dst`.
+    ///
+    /// Returns the moved message, or `None` if `src` is empty.
+    fn move_value(&self, src: &Identifier, dst: &Identifier) -> Result<Option<Message>> {
+        let mut moved = None;
+
+        self.transaction(&mut |tx| {
+            moved = tx.move_value(src, dst)?;
+            Ok(())
+        })?;
+
+        Ok(moved)
+    }
+
+    /// Moves a message from `src` to `dst`, trying again after each write
+    /// to the storage until `timeout` passes if `src` is empty.
+    fn move_or_wait(
+        &self,
+        src: &Identifier,
+        dst: &Identifier,
+        timeout: Duration,
+    ) -> Result<Option<Message>> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let seen = self.notifier().changes()?;
+
+            if let Some(message) = self.move_value(src, dst)? {
+                return Ok(Some(message));
+            }
+
+            if !self.notifier().wait(seen, deadline)? {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Changes the settings of a queue with `f`, creating the queue if it
+    /// doesn't exist. Values already in the queue are kept even if it ends up
+    /// holding more than its new `max_length`.
+    fn update_config(&self, id: &Identifier, f: &mut dyn FnMut(&mut QueueConfig)) -> Result<()> {
+        self.transaction(&mut |tx| tx.update_config(id, f))?;
+
+        Ok(())
+    }
+
+    /// Enqueues a value, trying again after each write to the storage until
+    /// the queue has room if it is full and its overflow policy is `block`.
+    /// Fails with `QueueFull` once `timeout`, or `BLOCK_TIMEOUT` without one,
+    /// passes without room.
+    fn enqueue_or_wait(
+        &self,
+        id: &Identifier,
+        value: Value,
+        envelope: Envelope,
+        timeout: Option<Duration>,
+    ) -> Result<Enqueued> {
+        let deadline = Instant::now() + timeout.unwrap_or(BLOCK_TIMEOUT);
+
+        loop {
+            let seen = self.notifier().changes()?;
+
+            match self.enqueue_message(id, value.clone(), envelope.clone()) {
+                Err(e)
+                    if matches!(e.downcast_ref(), Some(StorageError::QueueFull(_)))
+                        && self.config(id)?.overflow == Overflow::Block =>
+                {
+                    if !self.notifier().wait(seen, deadline)? {
+                        return Err(e);
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// The values of some messages, for comparing them in tests.
+#[cfg(test)]
+pub(crate) fn values(messages: Vec<Message>) -> Vec<Value> {
+    messages.into_iter().map(|message| message.value).collect()
+}
+
+#[test]
+fn notifier_wakes_waiters_after_a_change() -> Result<()> {
+    let notifier = std::sync::Arc::new(Notifier::default());
+    let seen = notifier.changes()?;
+    assert!(!notifier.wait(seen, Instant::now() + Duration::from_millis(10))?);
+
+    let other = notifier.clone();
+    let handle = std::thread::spawn(move || other.notify());
+    assert!(notifier.wait(seen, Instant::now() + Duration::from_secs(5))?);
+    handle.join().unwrap();
+
+    Ok(())
+}
+
+#[test]
+fn most_specific_template_applies() {
+    let mut templates = Templates::new();
+    templates.insert("*".into(), QueueConfig::default());
+    templates.insert(
+        "orders_*".into(),
+        QueueConfig {
+            max_length: Some(10),
+            overflow: Overflow::Block,
+            ..QueueConfig::default()
+        },
+    );
+
+    assert_eq!(
+        template_config(&templates, &"orders_eu".into()).max_length,
+        Some(10)
+    );
+    assert_eq!(template_config(&templates, &"jobs".into()).max_length, None);
+    assert_eq!(
+        template_config(&Templates::new(), &"jobs".into()),
+        QueueConfig::default()
+    );
+}
+
+#[test]
+fn dedup_ids_expire_after_their_window() {
+    let mut recent = RecentIds::default();
+    recent.record("a", 1, 0, 100);
+    recent.record("b", 2, 50, 100);
+
+    assert_eq!(recent.get("a", 100, 99), Some(1));
+    assert_eq!(recent.get("a", 100, 100), None);
+
+    recent.record("a", 3, 120, 100);
+    assert_eq!(recent.get("a", 100, 120), Some(3));
+    assert_eq!(recent.get("b", 100, 120), Some(2));
+
+    recent.record("c", 4, 200, 100);
+    assert_eq!(recent.ids.len(), 2);
+    assert_eq!(recent.order.len(), 2);
+    assert_eq!(
+        recent.iter().collect::<Vec<_>>(),
+        vec![("a", 3, 120), ("c", 4, 200)]
+    );
 }
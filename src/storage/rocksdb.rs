@@ -1,12 +1,26 @@
-use std::{collections::VecDeque, sync::Arc};
+use std::{
+    collections::VecDeque,
+    fmt,
+    path::Path,
+    sync::{Arc, Mutex},
+};
 
-use anyhow::Result;
-use rocksdb::{MergeOperands, Options, DB};
-use serde::{Deserialize, Serialize};
+use anyhow::{bail, Result};
+use bincode::Options as _;
+use rocksdb::{Direction, IteratorMode, MergeOperands, Options, WriteBatch, DB};
+use serde::{
+    de::{DeserializeSeed, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
 use structopt::StructOpt;
+use tracing::error;
 
 use crate::errors::*;
-use crate::storage::StorageBackend;
+use crate::storage::{
+    now, oldest_index, template_config, End, Enqueued, Envelope, Headers, Message, Metadata,
+    Notifier, Queue, QueueConfig, QueueInfo, RecentIds, Snapshot, StorageBackend, Templates,
+    Transaction, Write,
+};
 use crate::types::*;
 
 #[derive(Debug, Clone, StructOpt)]
@@ -18,98 +32,982 @@ pub struct StorageOptions {
 #[derive(Debug, Clone)]
 pub struct RocksDBStorage {
     db: Arc<DB>,
+    /// Held by every write, so that transactions and commands that read a
+    /// queue before changing it see no writes in between.
+    lock: Arc<Mutex<()>>,
+    notifier: Arc<Notifier>,
 }
 
+/// The metadata of a queue is stored under its name with this prefix.
+/// Identifiers can't contain it, and it sorts after every character they can
+/// contain, so all queue keys come before all metadata keys.
+const METADATA_PREFIX: &str = "~";
+
+fn metadata_key(id: &Identifier) -> String {
+    format!("{}{}", METADATA_PREFIX, id)
+}
+
+/// Templates are stored under their pattern with this prefix. No identifier
+/// starts with `~`, so these keys never clash with metadata keys.
+const TEMPLATE_PREFIX: &str = "~~";
+
+fn template_key(pattern: &str) -> String {
+    format!("{}{}", TEMPLATE_PREFIX, pattern)
+}
+
+/// The recent dedup ids of a queue are stored one per key, under this
+/// prefix, the name of the queue and a `/`, so that an enqueue only writes
+/// its own. No identifier starts with `@` or contains `/`. Each holds the id
+/// of its message and when it was enqueued.
+const DEDUP_PREFIX: &str = "~@";
+
+fn dedup_key(id: &Identifier, dedup_id: &str) -> String {
+    format!("{}{}/{}", DEDUP_PREFIX, id, dedup_id)
+}
+
+/// The range of the dedup keys of the queue `id`: `0` comes right after `/`.
+fn dedup_range(id: &Identifier) -> (String, String) {
+    (
+        format!("{}{}/", DEDUP_PREFIX, id),
+        format!("{}{}0", DEDUP_PREFIX, id),
+    )
+}
+
+/// Where the id of the next enqueued message is stored. No identifier starts
+/// with `#`, and it sorts before the template prefix.
+const NEXT_ID_KEY: &str = "~#next_id";
+
+/// Where the version of the format queues are stored in is kept. Databases
+/// without it are from before messages were stored, and hold bare values.
+const FORMAT_KEY: &str = "~#format";
+const FORMAT_VERSION: u32 = 1;
+
+type MergeFn = fn(&[u8], Option<&[u8]>, &mut MergeOperands) -> Option<Vec<u8>>;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Operation {
+    Enqueue(Message),
+    PushFront(Message),
+    Dequeue,
+    PopBack,
+    /// Removes the message with this id, wherever it is in the queue, as
+    /// when the messages before it are in held groups.
+    Remove(u64),
+    /// Removes the oldest message, to make room in a full queue.
+    DropOldest,
+}
+
+/// Decodes `count` messages of a stored queue from position `start`, and
+/// stops there, leaving the rest of it undecoded.
+struct Page {
+    start: usize,
+    count: usize,
+}
+
+impl<'de> DeserializeSeed<'de> for Page {
+    type Value = Vec<Message>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for Page {
+    type Value = Vec<Message>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a queue of messages")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut messages = vec![];
+        let mut index = 0;
+
+        while messages.len() < self.count {
+            match seq.next_element::<Message>()? {
+                Some(message) if index >= self.start => messages.push(message),
+                Some(_) => {}
+                None => break,
+            }
+
+            index += 1;
+        }
+
+        Ok(messages)
+    }
+}
+
+/// How queues changed before messages were stored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum LegacyOperation {
     Enqueue(Value),
     Dequeue,
 }
 
+/// Decodes part of a merge. A merge can't fail with an error, so what can't
+/// be decoded is logged, and the merge fails by returning `None`.
+fn decode_merge<'a, T: Deserialize<'a>>(key: &[u8], bytes: &'a [u8]) -> Option<T> {
+    match bincode::deserialize(bytes) {
+        Ok(decoded) => Some(decoded),
+        Err(e) => {
+            error!(error = %e, key = ?key, bytes = ?bytes, "Failed to decode a queue merge");
+            None
+        }
+    }
+}
+
+fn encode_merge<T: Serialize>(key: &[u8], value: &T) -> Option<Vec<u8>> {
+    match bincode::serialize(value) {
+        Ok(encoded) => Some(encoded),
+        Err(e) => {
+            error!(error = %e, key = ?key, "Failed to encode a queue merge");
+            None
+        }
+    }
+}
+
 pub fn merge_queue(
-    _new_key: &[u8],
+    new_key: &[u8],
     existing_val: Option<&[u8]>,
     operands: &mut MergeOperands,
 ) -> Option<Vec<u8>> {
-    let mut current: VecDeque<Value> = match existing_val {
-        Some(val) => bincode::deserialize::<VecDeque<Value>>(val).unwrap(),
+    let mut current: VecDeque<Message> = match existing_val {
+        Some(val) => decode_merge(new_key, val)?,
         None => VecDeque::new(),
     };
 
     for op in operands {
-        match bincode::deserialize::<Operation>(op).unwrap() {
-            Operation::Enqueue(v) => {
-                current.push_back(v);
+        match decode_merge(new_key, op)? {
+            Operation::Enqueue(message) => {
+                current.push_back(message);
+            }
+            Operation::PushFront(message) => {
+                current.push_front(message);
             }
             Operation::Dequeue => {
                 current.pop_front();
             }
+            Operation::PopBack => {
+                current.pop_back();
+            }
+            Operation::Remove(id) => {
+                current.retain(|message| message.id != id);
+            }
+            Operation::DropOldest => {
+                if let Some(index) = oldest_index(&current) {
+                    current.remove(index);
+                }
+            }
+        }
+    }
+
+    encode_merge(new_key, &current)
+}
+
+/// The merge operator of databases that hold bare values, only used to
+/// read them once so that they can be migrated.
+fn merge_legacy_queue(
+    new_key: &[u8],
+    existing_val: Option<&[u8]>,
+    operands: &mut MergeOperands,
+) -> Option<Vec<u8>> {
+    let mut current: VecDeque<Value> = match existing_val {
+        Some(val) => decode_merge(new_key, val)?,
+        None => VecDeque::new(),
+    };
+
+    for op in operands {
+        match decode_merge(new_key, op)? {
+            LegacyOperation::Enqueue(value) => current.push_back(value),
+            LegacyOperation::Dequeue => {
+                current.pop_front();
+            }
         }
     }
 
-    Some(bincode::serialize(&current).unwrap())
+    encode_merge(new_key, &current)
 }
 
 impl RocksDBStorage {
     #[tracing::instrument]
     pub fn init(path: &str) -> Result<Self> {
+        if Self::format(path)?.is_none() {
+            Self::migrate(path)?;
+        }
+
+        let db = Self::open(path, merge_queue)?;
+
         Ok(Self {
-            db: Arc::new(
-                DB::open(&Self::default_options(), path)
-                    .map_err(|_| StorageError::FailedInitialize)?,
-            ),
+            db: Arc::new(db),
+            lock: Arc::new(Mutex::new(())),
+            notifier: Arc::new(Notifier::default()),
         })
     }
 
-    fn default_options() -> Options {
+    fn open(path: &str, merge: MergeFn) -> Result<DB> {
         let mut opts = Options::default();
         opts.create_if_missing(true);
-        opts.set_merge_operator_associative("queue merge operator", merge_queue);
-        opts
+        opts.set_merge_operator_associative("queue merge operator", merge);
+
+        Ok(DB::open(&opts, path).map_err(|_| StorageError::FailedInitialize)?)
+    }
+
+    /// The format version of the database at `path`, or `None` if it
+    /// predates them or doesn't exist yet. Fails if the database is from a
+    /// newer version of xq. It is opened read-only and without a merge
+    /// operator, so that no merge runs before the format of its queues is
+    /// known.
+    fn format(path: &str) -> Result<Option<u32>> {
+        if !Path::new(path).join("CURRENT").exists() {
+            return Ok(None);
+        }
+
+        let db = DB::open_for_read_only(&Options::default(), path, false)
+            .map_err(|_| StorageError::FailedInitialize)?;
+
+        match db.get(FORMAT_KEY)? {
+            Some(version) => match bincode::deserialize::<u32>(&version)? {
+                FORMAT_VERSION => Ok(Some(FORMAT_VERSION)),
+                version => bail!(StorageError::UnsupportedFormat(version)),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Stores the bare values of every queue as messages with new ids, in
+    /// the order of the queues and their values. The database is opened with
+    /// the legacy merge operator, so that pending operations on the values
+    /// are applied first.
+    fn migrate(path: &str) -> Result<()> {
+        let db = Self::open(path, merge_legacy_queue)?;
+        let mut batch = WriteBatch::default();
+        let mut next_id = 1;
+
+        for (key, value) in db.iterator(IteratorMode::Start) {
+            if key.starts_with(METADATA_PREFIX.as_bytes()) {
+                break;
+            }
+
+            let mut messages = VecDeque::new();
+
+            for value in bincode::deserialize::<VecDeque<Value>>(&value)? {
+                messages.push_back(Message::new(next_id, value, Headers::new()));
+                next_id += 1;
+            }
+
+            batch.put(&key, bincode::serialize(&messages)?);
+        }
+
+        batch.put(NEXT_ID_KEY, bincode::serialize(&next_id)?);
+        batch.put(FORMAT_KEY, bincode::serialize(&FORMAT_VERSION)?);
+        db.write(batch)?;
+
+        Ok(())
+    }
+
+    fn values(&self, id: &Identifier) -> Result<Option<VecDeque<Message>>> {
+        match self.db.get(&id.0)? {
+            Some(v) => Ok(Some(bincode::deserialize::<VecDeque<Message>>(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The metadata of a queue, or `None` if it doesn't exist. Queues stored
+    /// before metadata was kept get metadata as if they were created now.
+    fn metadata(&self, id: &Identifier) -> Result<Option<Metadata>> {
+        if let Some(meta) = self.db.get(metadata_key(id))? {
+            return Ok(Some(bincode::deserialize::<Metadata>(&meta)?));
+        }
+
+        Ok(self.values(id)?.map(|values| Metadata::new(values.len())))
+    }
+
+    fn get(&self, id: &Identifier) -> Result<Option<Queue>> {
+        let values = match self.values(id)? {
+            Some(values) => values,
+            None => return Ok(None),
+        };
+
+        let meta = match self.db.get(metadata_key(id))? {
+            Some(meta) => bincode::deserialize::<Metadata>(&meta)?,
+            None => Metadata::new(values.len()),
+        };
+
+        Ok(Some(Queue { values, meta }))
+    }
+
+    /// Like `get`, but with the recent dedup ids of the queue in its
+    /// metadata, for writes that keep them. Must be called with the lock
+    /// held.
+    fn load(&self, id: &Identifier) -> Result<Option<Queue>> {
+        let mut queue = match self.get(id)? {
+            Some(queue) => queue,
+            None => return Ok(None),
+        };
+        self.load_recent(id, &mut queue.meta)?;
+
+        Ok(Some(queue))
+    }
+
+    /// Reads the recent dedup ids of the queue `id` into `meta`. Those that
+    /// expired are deleted on the way, as nothing else deletes them unless
+    /// their dedup id is enqueued again. Must be called with the lock held.
+    fn load_recent(&self, id: &Identifier, meta: &mut Metadata) -> Result<()> {
+        let (start, _) = dedup_range(id);
+        let mode = IteratorMode::From(start.as_bytes(), Direction::Forward);
+        let window = meta.config.dedup_window.saturating_mul(1000);
+        let now = now();
+        let mut recent = vec![];
+
+        for (key, value) in self.db.iterator(mode) {
+            let dedup_id = match key.strip_prefix(start.as_bytes()) {
+                Some(dedup_id) => String::from_utf8(dedup_id.to_vec())?,
+                None => break,
+            };
+            let (message_id, at) = bincode::deserialize::<(u64, u64)>(&value)?;
+
+            if at.saturating_add(window) > now {
+                recent.push((at, dedup_id, message_id));
+            } else {
+                self.db.delete(&key)?;
+            }
+        }
+
+        recent.sort();
+        meta.recent = RecentIds::default();
+
+        for (at, dedup_id, message_id) in recent {
+            meta.recent.record(&dedup_id, message_id, at, window);
+        }
+
+        Ok(())
+    }
+
+    /// The id of the message enqueued into `id` with `dedup_id` within the
+    /// dedup window of the queue, if any. Only the key of `dedup_id` is read.
+    fn duplicate_of(&self, id: &Identifier, dedup_id: &str) -> Result<Option<u64>> {
+        let meta = match self.metadata(id)? {
+            Some(meta) => meta,
+            None => return Ok(None),
+        };
+        let window = meta.config.dedup_window.saturating_mul(1000);
+
+        match self.db.get(dedup_key(id, dedup_id))? {
+            Some(value) => {
+                let (message_id, at) = bincode::deserialize::<(u64, u64)>(&value)?;
+                Ok(Some(message_id).filter(|_| at.saturating_add(window) > now()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Adds the writes that store `queue` as the queue `id` to `batch`. The
+    /// dedup ids it had are replaced by those of `queue`, so it must have
+    /// been read with `load` if they are to be kept.
+    fn put(batch: &mut WriteBatch, id: &Identifier, queue: &Queue) -> Result<()> {
+        let (start, end) = dedup_range(id);
+
+        batch.delete_range(start, end);
+        batch.put(&id.0, bincode::serialize(&queue.values)?);
+        Self::put_metadata(batch, id, &queue.meta)
+    }
+
+    /// Adds the writes that store `meta` as the metadata of the queue `id` to
+    /// `batch`. Its recent dedup ids are written under their own keys, and
+    /// left out of the rest, which so stays small.
+    fn put_metadata(batch: &mut WriteBatch, id: &Identifier, meta: &Metadata) -> Result<()> {
+        for (dedup_id, message_id, at) in meta.recent.iter() {
+            batch.put(
+                dedup_key(id, dedup_id),
+                bincode::serialize(&(message_id, at))?,
+            );
+        }
+
+        let meta = Metadata {
+            recent: RecentIds::default(),
+            ..meta.clone()
+        };
+        batch.put(metadata_key(id), bincode::serialize(&meta)?);
+
+        Ok(())
+    }
+
+    fn remove(batch: &mut WriteBatch, id: &Identifier) {
+        let (start, end) = dedup_range(id);
+
+        batch.delete(&id.0);
+        batch.delete(metadata_key(id));
+        batch.delete_range(start, end);
+    }
+
+    /// Writes `batch`, then wakes the commands waiting for a queue to
+    /// change.
+    fn write(&self, batch: WriteBatch) -> Result<()> {
+        self.db.write(batch)?;
+        self.notifier.notify();
+
+        Ok(())
+    }
+
+    /// The id the next enqueued message gets.
+    fn next_id(&self) -> Result<u64> {
+        match self.db.get(NEXT_ID_KEY)? {
+            Some(next_id) => Ok(bincode::deserialize::<u64>(&next_id)?),
+            None => Ok(1),
+        }
+    }
+
+    /// Adds the writes that push `message` onto `end` of the queue `id` to
+    /// `batch`. Must be called with the lock held.
+    fn push_batch(
+        &self,
+        batch: &mut WriteBatch,
+        id: &Identifier,
+        message: Message,
+        end: End,
+    ) -> Result<()> {
+        let mut meta = match self.metadata(id)? {
+            Some(meta) => meta,
+            None => Metadata::configured(template_config(&self.templates()?, id)),
+        };
+        meta.config.check_type(&message.value)?;
+        let dropped = meta.config.make_room(id, meta.length)?;
+        meta.record_enqueue(&message);
+
+        let next_id = self.next_id()?.max(message.id + 1);
+
+        for _ in 0..dropped {
+            meta.record_drop();
+            batch.merge(&id.0, bincode::serialize(&Operation::DropOldest)?);
+        }
+
+        let push = match end {
+            End::Front => Operation::PushFront(message),
+            End::Back => Operation::Enqueue(message),
+        };
+        batch.merge(&id.0, bincode::serialize(&push)?);
+
+        Self::put_metadata(batch, id, &meta)?;
+        batch.put(NEXT_ID_KEY, bincode::serialize(&next_id)?);
+
+        Ok(())
+    }
+
+    /// Takes the next message to deliver from `end` of the queue `id`, or
+    /// from its head if `end` is `None`.
+    fn take(&self, id: &Identifier, end: Option<End>) -> Result<Option<Message>> {
+        let _lock = self.lock.lock().map_err(|_| StorageError::FailedLock)?;
+
+        // Taking from an empty or missing queue, or one whose messages are
+        // all in held groups, must not write anything, or it would create
+        // the queue.
+        let mut queue = match self.get(id)? {
+            Some(queue) => queue,
+            None => return Ok(None),
+        };
+        let end = end.unwrap_or(queue.meta.head());
+        let index = match queue.meta.next_index(queue.values.iter(), end) {
+            Some(index) => index,
+            None => return Ok(None),
+        };
+        let last = queue.values.len() - 1;
+        let message = queue.values.remove(index).unwrap();
+        queue.meta.record_delivery(&message);
+
+        let operation = match index {
+            0 => Operation::Dequeue,
+            _ if index == last => Operation::PopBack,
+            _ => Operation::Remove(message.id),
+        };
+
+        let mut batch = WriteBatch::default();
+        batch.merge(&id.0, bincode::serialize(&operation)?);
+        Self::put_metadata(&mut batch, id, &queue.meta)?;
+        self.write(batch)?;
+
+        Ok(Some(message.delivered()))
+    }
+
+    /// The message the next take from `end` would return.
+    fn look(&self, id: &Identifier, end: Option<End>) -> Result<Option<Message>> {
+        Ok(self.get(id)?.and_then(|mut queue| {
+            let end = end.unwrap_or(queue.meta.head());
+            let index = queue.meta.next_index(queue.values.iter(), end)?;
+            queue.values.remove(index)
+        }))
     }
 }
 
 #[async_trait::async_trait]
 impl StorageBackend for RocksDBStorage {
     #[tracing::instrument]
-    fn enqueue(&self, id: &Identifier, value: Value) -> Result<()> {
-        let op = bincode::serialize(&Operation::Enqueue(value))?;
-        self.db.merge(&id.0, op)?;
+    fn enqueue_message(
+        &self,
+        id: &Identifier,
+        value: Value,
+        envelope: Envelope,
+    ) -> Result<Enqueued> {
+        let _lock = self.lock.lock().map_err(|_| StorageError::FailedLock)?;
+
+        let duplicate = match &envelope.dedup_id {
+            Some(dedup_id) => self.duplicate_of(id, dedup_id)?,
+            None => None,
+        };
+
+        if let Some(original) = duplicate {
+            return Ok(Enqueued::Duplicate(original));
+        }
+
+        let message = envelope.into_message(self.next_id()?, value);
+
+        let mut batch = WriteBatch::default();
+        self.push_batch(&mut batch, id, message.clone(), End::Back)?;
+        self.write(batch)?;
+
+        Ok(Enqueued::Message(message))
+    }
+
+    #[tracing::instrument]
+    fn push(&self, id: &Identifier, message: Message) -> Result<()> {
+        let _lock = self.lock.lock().map_err(|_| StorageError::FailedLock)?;
+
+        let mut batch = WriteBatch::default();
+        self.push_batch(&mut batch, id, message, End::Back)?;
+        self.write(batch)?;
 
         Ok(())
     }
 
     #[tracing::instrument]
-    fn dequeue(&self, id: &Identifier) -> Result<Value> {
-        let val = self.peek(id)?;
-        let op = bincode::serialize(&Operation::Dequeue)?;
+    fn enqueue_front(&self, id: &Identifier, value: Value, headers: Headers) -> Result<Message> {
+        let _lock = self.lock.lock().map_err(|_| StorageError::FailedLock)?;
+
+        let message = Message::new(self.next_id()?, value, headers);
+
+        let mut batch = WriteBatch::default();
+        self.push_batch(&mut batch, id, message.clone(), End::Front)?;
+        self.write(batch)?;
+
+        Ok(message)
+    }
+
+    #[tracing::instrument]
+    fn push_front(&self, id: &Identifier, message: Message) -> Result<()> {
+        let _lock = self.lock.lock().map_err(|_| StorageError::FailedLock)?;
+
+        let mut batch = WriteBatch::default();
+        self.push_batch(&mut batch, id, message, End::Front)?;
+        self.write(batch)?;
 
-        self.db.merge(&id.0, op)?;
+        Ok(())
+    }
+
+    #[tracing::instrument]
+    fn dequeue(&self, id: &Identifier) -> Result<Option<Message>> {
+        self.take(id, None)
+    }
 
-        Ok(val)
+    #[tracing::instrument]
+    fn pop_back(&self, id: &Identifier) -> Result<Option<Message>> {
+        self.take(id, Some(End::Back))
     }
 
     #[tracing::instrument]
     fn length(&self, id: &Identifier) -> Result<usize> {
-        let db = self.db.clone();
+        Ok(self.metadata(id)?.map_or(0, |meta| meta.length))
+    }
+
+    #[tracing::instrument]
+    fn peek(&self, id: &Identifier) -> Result<Option<Message>> {
+        self.look(id, None)
+    }
 
-        match db.get(&id.0)? {
-            Some(v) => Ok(bincode::deserialize::<Vec<Value>>(&v)?.len()),
-            None => Ok(0),
+    #[tracing::instrument]
+    fn peek_back(&self, id: &Identifier) -> Result<Option<Message>> {
+        self.look(id, Some(End::Back))
+    }
+
+    #[tracing::instrument]
+    fn range(&self, id: &Identifier, start: usize, count: usize) -> Result<Vec<Message>> {
+        // Each queue is stored under a single key, so the messages before
+        // `start` are decoded too, but none after the page.
+        match self.db.get_pinned(&id.0)? {
+            Some(bytes) => {
+                let options = bincode::options()
+                    .with_fixint_encoding()
+                    .allow_trailing_bytes();
+                let mut deserializer = bincode::Deserializer::from_slice(&bytes, options);
+
+                Ok(Page { start, count }.deserialize(&mut deserializer)?)
+            }
+            None => Ok(vec![]),
         }
     }
 
     #[tracing::instrument]
-    fn peek(&self, id: &Identifier) -> Result<Value> {
-        match self.db.get(&id.0)? {
-            Some(v) => {
-                let value = bincode::deserialize::<Vec<Value>>(&v)?;
-                match value.first() {
-                    Some(v) => Ok(v.clone()),
-                    None => Ok(Value::Null),
-                }
+    fn purge(&self, id: &Identifier) -> Result<usize> {
+        let _lock = self.lock.lock().map_err(|_| StorageError::FailedLock)?;
+
+        let mut queue = match self.load(id)? {
+            Some(queue) => queue,
+            None => return Ok(0),
+        };
+        let purged = queue.values.len();
+        queue.values.clear();
+        queue.meta.record_purge();
+
+        let mut batch = WriteBatch::default();
+        Self::put(&mut batch, id, &queue)?;
+        self.write(batch)?;
+
+        Ok(purged)
+    }
+
+    #[tracing::instrument]
+    fn delete(&self, id: &Identifier) -> Result<bool> {
+        let _lock = self.lock.lock().map_err(|_| StorageError::FailedLock)?;
+
+        let existed = self.db.get(&id.0)?.is_some();
+
+        let mut batch = WriteBatch::default();
+        Self::remove(&mut batch, id);
+        self.write(batch)?;
+
+        Ok(existed)
+    }
+
+    #[tracing::instrument]
+    fn rename(&self, src: &Identifier, dst: &Identifier) -> Result<()> {
+        let _lock = self.lock.lock().map_err(|_| StorageError::FailedLock)?;
+
+        let queue = match self.load(src)? {
+            Some(queue) => queue,
+            None => bail!(StorageError::QueueNotFound(src.to_string())),
+        };
+
+        if src != dst {
+            let mut batch = WriteBatch::default();
+            Self::remove(&mut batch, src);
+            Self::put(&mut batch, dst, &queue)?;
+            self.write(batch)?;
+        }
+
+        Ok(())
+    }
+
+    #[tracing::instrument]
+    fn copy(&self, src: &Identifier, dst: &Identifier) -> Result<()> {
+        let _lock = self.lock.lock().map_err(|_| StorageError::FailedLock)?;
+
+        let queue = match self.load(src)? {
+            Some(queue) => queue,
+            None => bail!(StorageError::QueueNotFound(src.to_string())),
+        };
+
+        if src != dst {
+            let copy = Queue {
+                meta: queue.meta.copied(),
+                values: queue.values,
+            };
+
+            let mut batch = WriteBatch::default();
+            Self::put(&mut batch, dst, &copy)?;
+            self.write(batch)?;
+        }
+
+        Ok(())
+    }
+
+    #[tracing::instrument]
+    fn scan(&self, after: Option<&Identifier>, count: usize) -> Result<Vec<Identifier>> {
+        let mode = match after {
+            Some(after) => IteratorMode::From(after.0.as_bytes(), Direction::Forward),
+            None => IteratorMode::Start,
+        };
+
+        let mut ids = vec![];
+
+        for (key, _) in self.db.iterator(mode) {
+            if ids.len() >= count || key.starts_with(METADATA_PREFIX.as_bytes()) {
+                break;
+            }
+
+            let id = Identifier(String::from_utf8(key.to_vec())?);
+
+            if Some(&id) != after {
+                ids.push(id);
             }
-            None => Ok(Value::Null),
+        }
+
+        Ok(ids)
+    }
+
+    #[tracing::instrument]
+    fn info(&self, id: &Identifier) -> Result<Option<QueueInfo>> {
+        Ok(self.get(id)?.map(|queue| queue.meta.info(&queue.values)))
+    }
+
+    #[tracing::instrument]
+    fn config(&self, id: &Identifier) -> Result<QueueConfig> {
+        match self.metadata(id)? {
+            Some(meta) => Ok(meta.config),
+            None => Ok(template_config(&self.templates()?, id)),
         }
     }
+
+    #[tracing::instrument]
+    fn templates(&self) -> Result<Templates> {
+        let mode = IteratorMode::From(TEMPLATE_PREFIX.as_bytes(), Direction::Forward);
+        let mut templates = Templates::new();
+
+        for (key, value) in self.db.iterator(mode) {
+            let pattern = match key.strip_prefix(TEMPLATE_PREFIX.as_bytes()) {
+                Some(pattern) => String::from_utf8(pattern.to_vec())?,
+                None => break,
+            };
+
+            templates.insert(pattern, bincode::deserialize::<QueueConfig>(&value)?);
+        }
+
+        Ok(templates)
+    }
+
+    #[tracing::instrument(skip(f))]
+    fn update_template(&self, pattern: &str, f: &mut dyn FnMut(&mut QueueConfig)) -> Result<()> {
+        let _lock = self.lock.lock().map_err(|_| StorageError::FailedLock)?;
+
+        let mut config = match self.db.get(template_key(pattern))? {
+            Some(config) => bincode::deserialize::<QueueConfig>(&config)?,
+            None => QueueConfig::default(),
+        };
+        f(&mut config);
+
+        self.db
+            .put(template_key(pattern), bincode::serialize(&config)?)?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument]
+    fn release(&self, id: &Identifier, group: &str) -> Result<bool> {
+        let _lock = self.lock.lock().map_err(|_| StorageError::FailedLock)?;
+
+        let mut meta = match self.metadata(id)? {
+            Some(meta) => meta,
+            None => return Ok(false),
+        };
+
+        if !meta.release(group) {
+            return Ok(false);
+        }
+
+        let mut batch = WriteBatch::default();
+        batch.put(metadata_key(id), bincode::serialize(&meta)?);
+        self.write(batch)?;
+
+        Ok(true)
+    }
+
+    #[tracing::instrument]
+    fn snapshot(&self) -> Result<Snapshot> {
+        // Taken so that the expired dedup ids can be deleted while reading.
+        let _lock = self.lock.lock().map_err(|_| StorageError::FailedLock)?;
+        let mut snapshot = Snapshot::default();
+
+        for (key, value) in self.db.iterator(IteratorMode::Start) {
+            if key.starts_with(METADATA_PREFIX.as_bytes()) {
+                break;
+            }
+
+            let id = Identifier(String::from_utf8(key.to_vec())?);
+            let values = bincode::deserialize::<Vec<Message>>(&value)?;
+
+            if let Some(mut meta) = self.metadata(&id)? {
+                self.load_recent(&id, &mut meta)?;
+                snapshot.metadata.insert(id.clone(), meta);
+            }
+
+            snapshot.queues.push((id, values));
+        }
+
+        snapshot.templates = self.templates()?;
+        snapshot.next_id = self.next_id()?;
+
+        Ok(snapshot)
+    }
+
+    #[tracing::instrument]
+    fn restore(&self, snapshot: Snapshot) -> Result<()> {
+        let _lock = self.lock.lock().map_err(|_| StorageError::FailedLock)?;
+        let mut batch = WriteBatch::default();
+
+        for (key, _) in self.db.iterator(IteratorMode::Start) {
+            batch.delete(key);
+        }
+
+        let mut metadata = snapshot.metadata;
+
+        for (id, values) in snapshot.queues {
+            let queue = Queue {
+                meta: metadata
+                    .remove(&id)
+                    .unwrap_or_else(|| Metadata::new(values.len())),
+                values: values.into(),
+            };
+
+            Self::put(&mut batch, &id, &queue)?;
+        }
+
+        for (pattern, config) in snapshot.templates {
+            batch.put(template_key(&pattern), bincode::serialize(&config)?);
+        }
+
+        batch.put(NEXT_ID_KEY, bincode::serialize(&snapshot.next_id)?);
+        batch.put(FORMAT_KEY, bincode::serialize(&FORMAT_VERSION)?);
+        self.write(batch)?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(f))]
+    fn transaction(&self, f: &mut dyn FnMut(&Transaction) -> Result<()>) -> Result<Vec<Write>> {
+        let _lock = self.lock.lock().map_err(|_| StorageError::FailedLock)?;
+
+        let source = |id: &Identifier| self.load(id);
+        let transaction = Transaction::new(&source, self.templates()?, self.next_id()?);
+
+        f(&transaction)?;
+        let (writes, changes, next_id) = transaction.commit()?;
+
+        // Nothing to write, and nobody to wake, as when a move finds its
+        // source empty.
+        if changes.is_empty() {
+            return Ok(writes);
+        }
+
+        let mut batch = WriteBatch::default();
+        batch.put(NEXT_ID_KEY, bincode::serialize(&next_id)?);
+
+        for (id, queue) in changes {
+            match queue {
+                Some(queue) => Self::put(&mut batch, &id, &queue)?,
+                None => Self::remove(&mut batch, &id),
+            }
+        }
+
+        self.write(batch)?;
+
+        Ok(writes)
+    }
+
+    fn notifier(&self) -> &Notifier {
+        &self.notifier
+    }
+}
+
+#[cfg(test)]
+fn temp_path(name: &str) -> String {
+    let path = std::env::temp_dir().join(format!("xq-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+    path.to_string_lossy().into_owned()
+}
+
+#[test]
+fn restored_database_opens_again() -> Result<()> {
+    let path = temp_path("restore");
+
+    let storage = RocksDBStorage::init(&path)?;
+    storage.enqueue(&"a".into(), 1.into())?;
+    let snapshot = storage.snapshot()?;
+    storage.enqueue(&"a".into(), 2.into())?;
+    storage.restore(snapshot)?;
+    drop(storage);
+
+    let storage = RocksDBStorage::init(&path)?;
+    assert_eq!(storage.dequeue(&"a".into())?.unwrap().value, 1.into());
+    assert_eq!(storage.dequeue(&"a".into())?, None);
+
+    std::fs::remove_dir_all(&path)?;
+    Ok(())
+}
+
+#[test]
+fn renamed_queues_replace_the_dedup_ids_of_their_destination() -> Result<()> {
+    let path = temp_path("rename");
+    let storage = RocksDBStorage::init(&path)?;
+    let dedup = |id: &str| Envelope {
+        dedup_id: Some(id.to_string()),
+        ..Envelope::default()
+    };
+
+    storage.enqueue_message(&"a".into(), 1.into(), dedup("x"))?;
+    let first = storage.enqueue_message(&"b".into(), 2.into(), dedup("y"))?;
+    storage.rename(&"a".into(), &"b".into())?;
+
+    let enqueued = storage.enqueue_message(&"b".into(), 2.into(), dedup("y"))?;
+    assert!(enqueued.id() > first.id());
+    let retried = storage.enqueue_message(&"b".into(), 1.into(), dedup("x"))?;
+    assert!(matches!(retried, Enqueued::Duplicate(_)));
+
+    // Purging keeps them.
+    storage.purge(&"b".into())?;
+    let retried = storage.enqueue_message(&"b".into(), 1.into(), dedup("x"))?;
+    assert!(matches!(retried, Enqueued::Duplicate(_)));
+
+    std::fs::remove_dir_all(&path)?;
+    Ok(())
+}
+
+#[test]
+fn range_and_length_read_part_of_a_queue() -> Result<()> {
+    let path = temp_path("range");
+    let storage = RocksDBStorage::init(&path)?;
+
+    for i in 0..5 {
+        storage.enqueue(&"a".into(), i.into())?;
+    }
+    storage.dequeue(&"a".into())?;
+
+    let values = |start, count| -> Result<Vec<Value>> {
+        Ok(storage
+            .range(&"a".into(), start, count)?
+            .into_iter()
+            .map(|message| message.value)
+            .collect())
+    };
+
+    assert_eq!(values(1, 2)?, vec![2.into(), 3.into()]);
+    assert_eq!(values(3, 10)?, vec![4.into()]);
+    assert_eq!(values(5, 10)?, vec![]);
+    assert_eq!(storage.length(&"a".into())?, 4);
+    assert_eq!(storage.length(&"b".into())?, 0);
+
+    std::fs::remove_dir_all(&path)?;
+    Ok(())
+}
+
+#[test]
+fn full_queues_drop_their_oldest_value() -> Result<()> {
+    let path = temp_path("drop_oldest");
+    let storage = RocksDBStorage::init(&path)?;
+    storage.update_config(&"a".into(), &mut |config| {
+        config.max_length = Some(2);
+        config.overflow = Overflow::DropOldest;
+    })?;
+
+    for value in 1..4 {
+        storage.enqueue(&"a".into(), value.into())?;
+    }
+    storage.enqueue_front(&"a".into(), 0.into(), Headers::new())?;
+
+    let values: Vec<Value> = storage
+        .range(&"a".into(), 0, 10)?
+        .into_iter()
+        .map(|message| message.value)
+        .collect();
+    assert_eq!(values, vec![0.into(), 3.into()]);
+    assert_eq!(storage.length(&"a".into())?, 2);
+
+    std::fs::remove_dir_all(&path)?;
+    Ok(())
 }
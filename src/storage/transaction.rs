@@ -0,0 +1,629 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::errors::*;
+use crate::storage::{
+    oldest_index, template_config, End, Enqueued, Envelope, Headers, Message, Metadata, Notifier,
+    QueueConfig, QueueInfo, Snapshot, StorageBackend, Templates,
+};
+use crate::types::*;
+
+/// A change made inside a transaction, applied to the storage on commit.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Write {
+    Enqueue(Identifier, Message),
+    PushFront(Identifier, Message),
+    Dequeue(Identifier),
+    PopBack(Identifier),
+    Purge(Identifier),
+    Delete(Identifier),
+    Rename(Identifier, Identifier),
+    Copy(Identifier, Identifier),
+    Configure(Identifier, QueueConfig),
+    Release(Identifier, String),
+}
+
+impl Write {
+    /// The queues whose contents this write changes.
+    fn changed(&self) -> Vec<&Identifier> {
+        match self {
+            Write::Enqueue(id, _)
+            | Write::PushFront(id, _)
+            | Write::Dequeue(id)
+            | Write::PopBack(id)
+            | Write::Purge(id)
+            | Write::Delete(id)
+            | Write::Configure(id, _)
+            | Write::Release(id, _) => vec![id],
+            Write::Rename(src, dst) => vec![src, dst],
+            Write::Copy(_, dst) => vec![dst],
+        }
+    }
+}
+
+/// A queue as seen by a transaction.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Queue {
+    pub values: VecDeque<Message>,
+    pub meta: Metadata,
+}
+
+/// Every queue a transaction has seen, or `None` for those that don't exist.
+pub(crate) type Queues = BTreeMap<Identifier, Option<Queue>>;
+
+#[derive(Debug, Default)]
+struct State {
+    queues: Queues,
+    writes: Vec<Write>,
+    next_id: u64,
+}
+
+impl State {
+    fn load(
+        &mut self,
+        source: &dyn Fn(&Identifier) -> Result<Option<Queue>>,
+        id: &Identifier,
+    ) -> Result<&mut Option<Queue>> {
+        if !self.queues.contains_key(id) {
+            self.queues.insert(id.clone(), source(id)?);
+        }
+
+        Ok(self.queues.get_mut(id).unwrap())
+    }
+}
+
+/// A view of the storage that buffers writes instead of applying them.
+///
+/// Queues are read from the storage the first time they are used, and from
+/// then on reflect the writes made in the transaction. Backends hold their
+/// lock for as long as a transaction runs, so nothing changes underneath it.
+pub struct Transaction<'a> {
+    source: &'a (dyn Fn(&Identifier) -> Result<Option<Queue>> + Sync),
+    /// The templates of the storage, for queues created in the transaction.
+    templates: Templates,
+    state: Mutex<State>,
+    /// Never notified, as waiting is rejected inside a transaction.
+    notifier: Notifier,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(
+        source: &'a (dyn Fn(&Identifier) -> Result<Option<Queue>> + Sync),
+        templates: Templates,
+        next_id: u64,
+    ) -> Self {
+        Self {
+            source,
+            templates,
+            state: Mutex::new(State {
+                next_id,
+                ..Default::default()
+            }),
+            notifier: Notifier::default(),
+        }
+    }
+
+    /// An empty queue named `id`, with the settings of its template.
+    fn create(&self, id: &Identifier) -> Queue {
+        Queue {
+            values: VecDeque::new(),
+            meta: Metadata::configured(template_config(&self.templates, id)),
+        }
+    }
+
+    /// Ends the transaction, returning its writes along with the final state
+    /// of every queue they changed and the next message id, for the backend
+    /// to store.
+    pub(crate) fn commit(self) -> Result<(Vec<Write>, Queues, u64)> {
+        let mut state = self
+            .state
+            .into_inner()
+            .map_err(|_| StorageError::FailedLock)?;
+
+        let mut changes = BTreeMap::new();
+
+        for id in state.writes.iter().flat_map(Write::changed) {
+            if let Some(queue) = state.queues.remove(id) {
+                changes.insert(id.clone(), queue);
+            }
+        }
+
+        Ok((state.writes, changes, state.next_id))
+    }
+
+    fn with_queue<R>(
+        &self,
+        id: &Identifier,
+        f: impl FnOnce(&mut Option<Queue>, &mut Vec<Write>) -> Result<R>,
+    ) -> Result<R> {
+        let mut state = self.state.lock().map_err(|_| StorageError::FailedLock)?;
+        state.load(self.source, id)?;
+
+        let State { queues, writes, .. } = &mut *state;
+        f(queues.get_mut(id).unwrap(), writes)
+    }
+
+    /// Replaces `dst` with the contents of `src`, taking them out of `src`
+    /// when renaming.
+    fn transfer(&self, src: &Identifier, dst: &Identifier, write: Write) -> Result<()> {
+        let mut state = self.state.lock().map_err(|_| StorageError::FailedLock)?;
+        let rename = matches!(write, Write::Rename(..));
+
+        let queue = match state.load(self.source, src)? {
+            Some(_) if src == dst => return Ok(()),
+            Some(queue) if rename => queue.clone(),
+            Some(queue) => Queue {
+                values: queue.values.clone(),
+                meta: queue.meta.copied(),
+            },
+            None => bail!(StorageError::QueueNotFound(src.to_string())),
+        };
+
+        if rename {
+            state.queues.insert(src.clone(), None);
+        }
+
+        state.queues.insert(dst.clone(), Some(queue));
+        state.writes.push(write);
+
+        Ok(())
+    }
+
+    /// Adds `message` to `end` of the queue `id`.
+    fn push_at(&self, id: &Identifier, message: Message, end: End) -> Result<()> {
+        self.with_queue(id, |queue, writes| {
+            let queue = queue.get_or_insert_with(|| self.create(id));
+            queue.meta.config.check_type(&message.value)?;
+            let dropped = queue.meta.config.make_room(id, queue.values.len())?;
+
+            for _ in 0..dropped {
+                if let Some(index) = oldest_index(&queue.values) {
+                    queue.values.remove(index);
+                    queue.meta.record_drop();
+                }
+            }
+
+            queue.meta.record_enqueue(&message);
+
+            match end {
+                End::Front => queue.values.push_front(message.clone()),
+                End::Back => queue.values.push_back(message.clone()),
+            }
+
+            writes.push(match end {
+                End::Front => Write::PushFront(id.clone(), message.clone()),
+                End::Back => Write::Enqueue(id.clone(), message.clone()),
+            });
+            Ok(())
+        })?;
+
+        let mut state = self.state.lock().map_err(|_| StorageError::FailedLock)?;
+        state.next_id = state.next_id.max(message.id + 1);
+
+        Ok(())
+    }
+
+    /// Takes the next message to deliver from `end` of the queue `id`, or
+    /// from its head if `end` is `None`.
+    fn take(&self, id: &Identifier, end: Option<End>) -> Result<Option<Message>> {
+        self.with_queue(id, |queue, writes| {
+            let Queue { values, meta } = match queue {
+                Some(queue) => queue,
+                None => return Ok(None),
+            };
+            let index = match meta.next_index(values.iter(), end.unwrap_or(meta.head())) {
+                Some(index) => index,
+                None => return Ok(None),
+            };
+
+            let message = values.remove(index).unwrap();
+            meta.record_delivery(&message);
+            writes.push(match end {
+                Some(End::Back) => Write::PopBack(id.clone()),
+                _ => Write::Dequeue(id.clone()),
+            });
+
+            Ok(Some(message.delivered()))
+        })
+    }
+
+    /// The message the next take from `end` would return.
+    fn look(&self, id: &Identifier, end: Option<End>) -> Result<Option<Message>> {
+        self.with_queue(id, |queue, _| {
+            Ok(queue.as_ref().and_then(|q| {
+                q.meta
+                    .next_index(q.values.iter(), end.unwrap_or(q.meta.head()))
+                    .map(|index| q.values[index].clone())
+            }))
+        })
+    }
+
+    /// Applies a write made by another transaction, as when replaying it on
+    /// a replica.
+    pub fn apply(&self, write: Write) -> Result<()> {
+        match write {
+            Write::Enqueue(id, message) => self.push(&id, message),
+            Write::PushFront(id, message) => self.push_front(&id, message),
+            Write::Dequeue(id) => self.dequeue(&id).map(|_| ()),
+            Write::PopBack(id) => self.pop_back(&id).map(|_| ()),
+            Write::Purge(id) => self.purge(&id).map(|_| ()),
+            Write::Delete(id) => self.delete(&id).map(|_| ()),
+            Write::Rename(src, dst) => self.rename(&src, &dst),
+            Write::Copy(src, dst) => self.copy(&src, &dst),
+            Write::Configure(id, config) => {
+                self.update_config(&id, &mut |current| *current = config.clone())
+            }
+            Write::Release(id, group) => self.release(&id, &group).map(|_| ()),
+        }
+    }
+}
+
+impl fmt::Debug for Transaction<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Transaction")
+            .field("state", &self.state)
+            .finish()
+    }
+}
+
+impl StorageBackend for Transaction<'_> {
+    fn enqueue_message(
+        &self,
+        id: &Identifier,
+        value: Value,
+        envelope: Envelope,
+    ) -> Result<Enqueued> {
+        let duplicate = self.with_queue(id, |queue, _| {
+            Ok(queue
+                .as_ref()
+                .and_then(|queue| queue.meta.duplicate_of(envelope.dedup_id.as_deref())))
+        })?;
+
+        if let Some(original) = duplicate {
+            return Ok(Enqueued::Duplicate(original));
+        }
+
+        let next_id = {
+            let state = self.state.lock().map_err(|_| StorageError::FailedLock)?;
+            state.next_id
+        };
+
+        let message = envelope.into_message(next_id, value);
+        self.push(id, message.clone())?;
+
+        Ok(Enqueued::Message(message))
+    }
+
+    fn push(&self, id: &Identifier, message: Message) -> Result<()> {
+        self.push_at(id, message, End::Back)
+    }
+
+    fn enqueue_front(&self, id: &Identifier, value: Value, headers: Headers) -> Result<Message> {
+        let next_id = {
+            let state = self.state.lock().map_err(|_| StorageError::FailedLock)?;
+            state.next_id
+        };
+
+        let message = Message::new(next_id, value, headers);
+        self.push_front(id, message.clone())?;
+
+        Ok(message)
+    }
+
+    fn push_front(&self, id: &Identifier, message: Message) -> Result<()> {
+        self.push_at(id, message, End::Front)
+    }
+
+    fn dequeue(&self, id: &Identifier) -> Result<Option<Message>> {
+        self.take(id, None)
+    }
+
+    fn pop_back(&self, id: &Identifier) -> Result<Option<Message>> {
+        self.take(id, Some(End::Back))
+    }
+
+    fn length(&self, id: &Identifier) -> Result<usize> {
+        self.with_queue(id, |queue, _| {
+            Ok(queue.as_ref().map_or(0, |q| q.values.len()))
+        })
+    }
+
+    fn peek(&self, id: &Identifier) -> Result<Option<Message>> {
+        self.look(id, None)
+    }
+
+    fn peek_back(&self, id: &Identifier) -> Result<Option<Message>> {
+        self.look(id, Some(End::Back))
+    }
+
+    fn range(&self, id: &Identifier, start: usize, count: usize) -> Result<Vec<Message>> {
+        self.with_queue(id, |queue, _| {
+            Ok(queue
+                .iter()
+                .flat_map(|q| &q.values)
+                .skip(start)
+                .take(count)
+                .cloned()
+                .collect())
+        })
+    }
+
+    fn purge(&self, id: &Identifier) -> Result<usize> {
+        self.with_queue(id, |queue, writes| match queue {
+            Some(queue) => {
+                let purged = queue.values.len();
+                queue.values.clear();
+                queue.meta.record_purge();
+                writes.push(Write::Purge(id.clone()));
+                Ok(purged)
+            }
+            None => Ok(0),
+        })
+    }
+
+    fn delete(&self, id: &Identifier) -> Result<bool> {
+        self.with_queue(id, |queue, writes| {
+            let existed = queue.take().is_some();
+
+            if existed {
+                writes.push(Write::Delete(id.clone()));
+            }
+
+            Ok(existed)
+        })
+    }
+
+    fn rename(&self, src: &Identifier, dst: &Identifier) -> Result<()> {
+        self.transfer(src, dst, Write::Rename(src.clone(), dst.clone()))
+    }
+
+    fn copy(&self, src: &Identifier, dst: &Identifier) -> Result<()> {
+        self.transfer(src, dst, Write::Copy(src.clone(), dst.clone()))
+    }
+
+    fn scan(&self, _after: Option<&Identifier>, _count: usize) -> Result<Vec<Identifier>> {
+        bail!(TransactionError::Unsupported)
+    }
+
+    fn info(&self, id: &Identifier) -> Result<Option<QueueInfo>> {
+        self.with_queue(id, |queue, _| {
+            Ok(queue.as_ref().map(|q| q.meta.info(&q.values)))
+        })
+    }
+
+    fn config(&self, id: &Identifier) -> Result<QueueConfig> {
+        self.with_queue(id, |queue, _| {
+            Ok(match queue {
+                Some(queue) => queue.meta.config.clone(),
+                None => template_config(&self.templates, id),
+            })
+        })
+    }
+
+    fn templates(&self) -> Result<Templates> {
+        Ok(self.templates.clone())
+    }
+
+    fn update_template(&self, _pattern: &str, _f: &mut dyn FnMut(&mut QueueConfig)) -> Result<()> {
+        bail!(TransactionError::Unsupported)
+    }
+
+    fn release(&self, id: &Identifier, group: &str) -> Result<bool> {
+        self.with_queue(id, |queue, writes| {
+            let released = queue
+                .as_mut()
+                .is_some_and(|queue| queue.meta.release(group));
+
+            if released {
+                writes.push(Write::Release(id.clone(), group.to_string()));
+            }
+
+            Ok(released)
+        })
+    }
+
+    fn snapshot(&self) -> Result<Snapshot> {
+        bail!(TransactionError::Unsupported)
+    }
+
+    fn restore(&self, _snapshot: Snapshot) -> Result<()> {
+        bail!(TransactionError::Unsupported)
+    }
+
+    fn move_value(&self, src: &Identifier, dst: &Identifier) -> Result<Option<Message>> {
+        let message = match self.dequeue(src)? {
+            Some(message) => message,
+            None => return Ok(None),
+        };
+
+        self.push(dst, message.clone())?;
+
+        Ok(Some(message))
+    }
+
+    fn update_config(&self, id: &Identifier, f: &mut dyn FnMut(&mut QueueConfig)) -> Result<()> {
+        self.with_queue(id, |queue, writes| {
+            let queue = queue.get_or_insert_with(|| self.create(id));
+            f(&mut queue.meta.config);
+            writes.push(Write::Configure(id.clone(), queue.meta.config.clone()));
+            Ok(())
+        })
+    }
+
+    /// Nothing else can dequeue while the transaction runs, so a full queue
+    /// fails the enqueue instead of waiting, and a timeout is rejected like
+    /// that of a move.
+    fn enqueue_or_wait(
+        &self,
+        id: &Identifier,
+        value: Value,
+        envelope: Envelope,
+        timeout: Option<Duration>,
+    ) -> Result<Enqueued> {
+        if timeout.is_some() {
+            bail!(TransactionError::Unsupported);
+        }
+
+        self.enqueue_message(id, value, envelope)
+    }
+
+    /// Nothing else can enqueue while the transaction runs, and waiting
+    /// would hold the storage lock, so waiting moves are rejected.
+    fn move_or_wait(
+        &self,
+        _src: &Identifier,
+        _dst: &Identifier,
+        _timeout: Duration,
+    ) -> Result<Option<Message>> {
+        bail!(TransactionError::Unsupported)
+    }
+
+    fn transaction(&self, _f: &mut dyn FnMut(&Transaction) -> Result<()>) -> Result<Vec<Write>> {
+        bail!(TransactionError::AlreadyStarted)
+    }
+
+    fn notifier(&self) -> &Notifier {
+        &self.notifier
+    }
+}
+
+#[cfg(test)]
+use crate::storage::values;
+
+#[cfg(test)]
+fn test_source(id: &Identifier) -> Result<Option<Queue>> {
+    Ok(match id.0.as_str() {
+        "a" => Some(Queue {
+            values: VecDeque::from(vec![
+                Message::new(1, 1.into(), Headers::new()),
+                Message::new(2, 2.into(), Headers::new()),
+            ]),
+            meta: Metadata::new(2),
+        }),
+        _ => None,
+    })
+}
+
+#[test]
+fn transaction_reads_its_own_writes() -> Result<()> {
+    let transaction = Transaction::new(&test_source, Templates::new(), 3);
+
+    let dequeued = transaction.dequeue(&"a".into())?.unwrap();
+    assert_eq!((dequeued.id, dequeued.deliveries), (1, 1));
+    let enqueued = transaction
+        .enqueue(&"b".into(), 1.into())?
+        .message()
+        .unwrap();
+    assert_eq!(enqueued.id, 3);
+    assert_eq!(transaction.dequeue(&"c".into())?, None);
+
+    assert_eq!(transaction.peek(&"a".into())?.unwrap().value, 2.into());
+    assert_eq!(transaction.length(&"b".into())?, 1);
+
+    let (writes, changes, next_id) = transaction.commit()?;
+    assert_eq!(
+        writes,
+        vec![
+            Write::Dequeue("a".into()),
+            Write::Enqueue("b".into(), enqueued)
+        ]
+    );
+    assert_eq!(changes.len(), 2);
+    assert!(!changes.contains_key(&"c".into()));
+    assert_eq!(next_id, 4);
+
+    Ok(())
+}
+
+#[test]
+fn transaction_renames_and_copies_queues() -> Result<()> {
+    let transaction = Transaction::new(&test_source, Templates::new(), 3);
+
+    transaction.copy(&"a".into(), &"b".into())?;
+    transaction.rename(&"a".into(), &"c".into())?;
+    transaction.dequeue(&"b".into())?;
+
+    assert!(transaction.rename(&"a".into(), &"d".into()).is_err());
+    assert_eq!(
+        values(transaction.range(&"b".into(), 0, 10)?),
+        vec![2.into()]
+    );
+    assert_eq!(
+        values(transaction.range(&"c".into(), 0, 10)?),
+        vec![1.into(), 2.into()]
+    );
+    assert!(transaction.delete(&"c".into())?);
+    assert!(!transaction.delete(&"c".into())?);
+
+    let info = transaction.info(&"b".into())?.unwrap();
+    assert_eq!((info.length, info.enqueued, info.dequeued), (1, 2, 1));
+
+    let (_, changes, _) = transaction.commit()?;
+    assert_eq!(changes.get(&"a".into()), Some(&None));
+    assert_eq!(changes.get(&"c".into()), Some(&None));
+
+    Ok(())
+}
+
+#[test]
+fn transaction_enforces_queue_limits() -> Result<()> {
+    let mut templates = Templates::new();
+    templates.insert(
+        "b*".into(),
+        QueueConfig {
+            max_length: Some(0),
+            overflow: Overflow::Reject,
+            ..QueueConfig::default()
+        },
+    );
+    let transaction = Transaction::new(&test_source, templates, 3);
+
+    assert_eq!(transaction.config(&"b".into())?.max_length, Some(0));
+    assert!(transaction.enqueue(&"b".into(), 1.into()).is_err());
+
+    transaction.update_config(&"a".into(), &mut |config| config.max_length = Some(2))?;
+    assert!(transaction
+        .enqueue_or_wait(&"a".into(), 3.into(), Envelope::default(), None)
+        .is_err());
+
+    transaction.update_config(&"a".into(), &mut |config| {
+        config.overflow = Overflow::DropOldest
+    })?;
+    let enqueued = transaction
+        .enqueue(&"a".into(), 3.into())?
+        .message()
+        .unwrap();
+    assert_eq!(
+        values(transaction.range(&"a".into(), 0, 10)?),
+        vec![2.into(), 3.into()]
+    );
+
+    let info = transaction.info(&"a".into())?.unwrap();
+    assert_eq!((info.length, info.enqueued, info.dequeued), (2, 3, 0));
+
+    let (writes, _, _) = transaction.commit()?;
+    assert_eq!(writes.last(), Some(&Write::Enqueue("a".into(), enqueued)));
+
+    Ok(())
+}
+
+#[test]
+fn pushed_messages_keep_later_ids_greater() -> Result<()> {
+    let transaction = Transaction::new(&test_source, Templates::new(), 3);
+
+    let mut message = Message::new(10, 1.into(), Headers::new());
+    message
+        .headers
+        .insert("trace".into(), "abc".to_string().into());
+    transaction.push(&"b".into(), message.clone())?;
+
+    assert_eq!(transaction.peek(&"b".into())?, Some(message));
+    assert_eq!(transaction.enqueue(&"b".into(), 2.into())?.id(), 11);
+
+    Ok(())
+}
@@ -1,13 +1,129 @@
-use std::{convert::From, fmt};
+use std::{collections::BTreeMap, convert::From, fmt};
 
 use serde::{Deserialize, Serialize};
 
+// New variants go at the end: bincode stores the variant index, and values
+// already persisted by the RocksDB backend must keep decoding to the same type.
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub enum Value {
     Integer(i64),
     Float(f64),
     String(String),
     Null,
+    Bool(bool),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    Map(BTreeMap<String, Value>),
+}
+
+impl Value {
+    pub fn value_type(&self) -> ValueType {
+        match self {
+            Value::Integer(_) => ValueType::Integer,
+            Value::Float(_) => ValueType::Float,
+            Value::String(_) => ValueType::String,
+            Value::Null => ValueType::Null,
+            Value::Bool(_) => ValueType::Bool,
+            Value::Bytes(_) => ValueType::Bytes,
+            Value::List(_) => ValueType::List,
+            Value::Map(_) => ValueType::Map,
+        }
+    }
+}
+
+fn write_quoted(f: &mut fmt::Formatter, v: &str) -> fmt::Result {
+    write!(f, "\"")?;
+
+    for c in v.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if c.is_control() => write!(f, "\\u{{{:x}}}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+
+    write!(f, "\"")
+}
+
+fn write_bytes(f: &mut fmt::Formatter, v: &[u8]) -> fmt::Result {
+    write!(f, "b\"")?;
+
+    for &b in v {
+        match b {
+            b'"' => write!(f, "\\\"")?,
+            b'\\' => write!(f, "\\\\")?,
+            b'\n' => write!(f, "\\n")?,
+            b'\r' => write!(f, "\\r")?,
+            b'\t' => write!(f, "\\t")?,
+            b' '..=b'~' => write!(f, "{}", b as char)?,
+            b => write!(f, "\\x{:02x}", b)?,
+        }
+    }
+
+    write!(f, "\"")
+}
+
+fn is_identifier(v: &str) -> bool {
+    let mut chars = v.chars();
+
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+/// Displays a value as an xq literal, which parses back to the same value.
+///
+/// This differs from the `Display` of `Value` itself, used for replies to
+/// clients, in that top-level strings are quoted and floats always keep
+/// their fractional part or exponent.
+pub struct Literal<'a>(pub &'a Value);
+
+impl fmt::Display for Literal<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Value::String(v) => write_quoted(f, v),
+            Value::Float(v) => write!(f, "{:?}", v),
+            Value::List(values) => {
+                write!(f, "[")?;
+
+                for (i, v) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+
+                    write!(f, "{}", Literal(v))?;
+                }
+
+                write!(f, "]")
+            }
+            Value::Map(entries) => {
+                write!(f, "{{")?;
+
+                for (i, (k, v)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+
+                    if is_identifier(k) {
+                        write!(f, "{}: {}", k, Literal(v))?;
+                    } else {
+                        write_quoted(f, k)?;
+                        write!(f, ": {}", Literal(v))?;
+                    }
+                }
+
+                write!(f, "}}")
+            }
+            v => write!(f, "{}", v),
+        }
+    }
 }
 
 impl fmt::Display for Value {
@@ -17,6 +133,9 @@ impl fmt::Display for Value {
             Value::Float(v) => write!(f, "{}", v),
             Value::String(v) => write!(f, "{}", v),
             Value::Null => write!(f, "null"),
+            Value::Bool(v) => write!(f, "{}", v),
+            Value::Bytes(v) => write_bytes(f, v),
+            v => write!(f, "{}", Literal(v)),
         }
     }
 }
@@ -45,7 +164,61 @@ impl From<f32> for Value {
     }
 }
 
-#[derive(Debug, PartialEq, Clone, PartialOrd, Ord, Eq, Hash)]
+impl From<bool> for Value {
+    fn from(item: bool) -> Self {
+        Value::Bool(item)
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(item: Vec<u8>) -> Self {
+        Value::Bytes(item)
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(item: Vec<Value>) -> Self {
+        Value::List(item)
+    }
+}
+
+impl From<BTreeMap<String, Value>> for Value {
+    fn from(item: BTreeMap<String, Value>) -> Self {
+        Value::Map(item)
+    }
+}
+
+/// The type of a value, used to restrict which values a queue accepts.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, Serialize, Deserialize)]
+pub enum ValueType {
+    Integer,
+    Float,
+    String,
+    Null,
+    Bool,
+    Bytes,
+    List,
+    Map,
+}
+
+impl fmt::Display for ValueType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ValueType::Integer => "integer",
+            ValueType::Float => "float",
+            ValueType::String => "string",
+            ValueType::Null => "null",
+            ValueType::Bool => "bool",
+            ValueType::Bytes => "bytes",
+            ValueType::List => "list",
+            ValueType::Map => "map",
+        };
+
+        write!(f, ":{}", name)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, PartialOrd, Ord, Eq, Hash, Serialize, Deserialize)]
 pub struct Identifier(pub String);
 
 impl From<&str> for Identifier {
@@ -56,7 +229,7 @@ impl From<&str> for Identifier {
 
 impl From<String> for Identifier {
     fn from(v: String) -> Self {
-        Identifier(v.into())
+        Identifier(v)
     }
 }
 
@@ -66,31 +239,419 @@ impl fmt::Display for Identifier {
     }
 }
 
+/// What enqueueing into a full queue does.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum Overflow {
+    /// Fail the enqueue.
+    #[default]
+    Reject,
+    /// Drop the oldest value to make room.
+    DropOldest,
+    /// Wait until a value is dequeued.
+    Block,
+}
+
+impl fmt::Display for Overflow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Overflow::Reject => "reject",
+            Overflow::DropOldest => "drop_oldest",
+            Overflow::Block => "block",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+/// Which end of a queue `dequeue` takes values from.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum Mode {
+    /// First in, first out: from the front.
+    #[default]
+    Fifo,
+    /// Last in, first out: from the back.
+    Stack,
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Mode::Fifo => "fifo",
+            Mode::Stack => "stack",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+/// How `dequeue` and `peek` reply: with just the value, or with the whole
+/// message, including its id and headers.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Reply {
+    Value,
+    Full,
+}
+
+/// A queue setting changed by `configure`.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Setting {
+    /// The most values the queue may hold, or null for no limit.
+    MaxLength(Expr),
+    Overflow(Overflow),
+    /// For how many seconds a dedup id is remembered.
+    DedupWindow(Expr),
+    Mode(Mode),
+    /// The type of the values the queue accepts, or `None` for any.
+    Type(Option<ValueType>),
+}
+
+impl fmt::Display for Setting {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Setting::MaxLength(max) => write!(f, "max_length {}", max),
+            Setting::Overflow(overflow) => write!(f, "overflow {}", overflow),
+            Setting::DedupWindow(window) => write!(f, "dedup_window {}", window),
+            Setting::Mode(mode) => write!(f, "mode {}", mode),
+            Setting::Type(Some(value_type)) => write!(f, "type {}", value_type),
+            Setting::Type(None) => write!(f, "type any"),
+        }
+    }
+}
+
+/// What `configure` and `config` work on: a queue, or the template that
+/// queues whose names match a glob pattern are created with.
+#[derive(Debug, PartialEq, Clone)]
+pub enum ConfigTarget {
+    Queue(Identifier),
+    Template(String),
+}
+
+impl fmt::Display for ConfigTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigTarget::Queue(id) => write!(f, "{}", id),
+            ConfigTarget::Template(pattern) => {
+                write!(f, "{}", Literal(&Value::String(pattern.clone())))
+            }
+        }
+    }
+}
+
+/// Anything that can be used where a value is expected: a literal, a
+/// variable bound with `let`, or the result of a command.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Expr {
+    Value(Value),
+    Variable(Identifier),
+    Command(Box<Command>),
+}
+
+impl From<Value> for Expr {
+    fn from(item: Value) -> Self {
+        Expr::Value(item)
+    }
+}
+
+impl From<Command> for Expr {
+    fn from(item: Command) -> Self {
+        Expr::Command(Box::new(item))
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::Value(v) => write!(f, "{}", Literal(v)),
+            Expr::Variable(id) => write!(f, "${}", id),
+            Expr::Command(cmd) => write!(f, "({})", cmd),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let op = match self {
+            Comparison::Equal => "==",
+            Comparison::NotEqual => "!=",
+            Comparison::Less => "<",
+            Comparison::LessOrEqual => "<=",
+            Comparison::Greater => ">",
+            Comparison::GreaterOrEqual => ">=",
+        };
+
+        write!(f, "{}", op)
+    }
+}
+
+/// The condition of an `if` or `while`, such as `(length a) > 0`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Condition {
+    pub left: Expr,
+    pub comparison: Comparison,
+    pub right: Expr,
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {}", self.left, self.comparison, self.right)
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Command {
-    Enqueue(Identifier, Value),
-    Dequeue(Identifier),
+    /// Enqueues a value, with headers, a dedup id and a group if there are
+    /// any. A full queue that blocks is waited on for at most `wait`
+    /// seconds, if given.
+    Enqueue {
+        queue: Identifier,
+        value: Expr,
+        headers: Option<Expr>,
+        dedup: Option<Expr>,
+        group: Option<Expr>,
+        wait: Option<Expr>,
+    },
+    Dequeue(Identifier, Reply),
+    /// Adds a value to the front of a queue, ahead of every other, with
+    /// headers if there are any.
+    PushFront(Identifier, Expr, Option<Expr>),
+    /// Takes the value at the back of a queue.
+    PopBack(Identifier, Reply),
     Length(Identifier),
-    Peek(Identifier),
-    Assert(Box<Command>, Value),
+    Peek(Identifier, Reply),
+    PeekBack(Identifier, Reply),
+    /// Up to a number of values of a queue, from a position onwards.
+    Range(Identifier, Expr, Expr),
+    /// Moves a value from one queue to another, waiting up to the given
+    /// number of seconds for one to arrive if there is a timeout.
+    Move(Identifier, Identifier, Option<Expr>),
+    /// Lets the next message of a group be dequeued.
+    Release(Identifier, Expr),
+    Purge(Identifier),
+    Delete(Identifier),
+    Rename(Identifier, Identifier),
+    Copy(Identifier, Identifier),
+    /// Every queue, or those whose names match a glob pattern.
+    Queues(Option<Expr>),
+    /// One page of queue names after a cursor, optionally matching a glob
+    /// pattern.
+    Scan(Expr, Option<Expr>),
+    Info(Identifier),
+    /// Creates a queue if it doesn't exist, optionally restricting the type
+    /// of its values and setting its mode.
+    Open(Identifier, Option<ValueType>, Option<Mode>),
+    /// Changes some settings of a queue or template, keeping the others.
+    Configure(ConfigTarget, Vec<Setting>),
+    /// The settings of a queue or template.
+    Config(ConfigTarget),
+    Assert(Box<Command>, Expr),
     AssertError(Box<Command>),
+    Let(Identifier, Expr),
+    Repeat(Expr, Vec<Command>),
+    While(Condition, Vec<Command>),
+    If(Condition, Vec<Command>, Vec<Command>),
+    Begin,
+    Commit,
+    Rollback,
+    Comment(String),
     Noop,
 }
 
+/// Writes a block of commands, one per line, indented one level deeper than
+/// the command that owns it.
+fn write_block(f: &mut fmt::Formatter, commands: &[Command], indent: usize) -> fmt::Result {
+    writeln!(f, "{{")?;
+
+    for command in commands {
+        if *command != Command::Noop {
+            write!(f, "{:indent$}", "", indent = indent + 4)?;
+            command.write_indented(f, indent + 4)?;
+        }
+
+        writeln!(f)?;
+    }
+
+    write!(f, "{:indent$}}}", "", indent = indent)
+}
+
+/// Displays a command as xq source, which parses back to the same command.
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write_indented(f, 0)
+    }
+}
+
 impl Command {
+    fn write_indented(&self, f: &mut fmt::Formatter, indent: usize) -> fmt::Result {
+        match self {
+            Command::Enqueue {
+                queue,
+                value,
+                headers,
+                dedup,
+                group,
+                wait,
+            } => {
+                write!(f, "enqueue {} {}", queue, value)?;
+
+                if let Some(headers) = headers {
+                    write!(f, " headers {}", headers)?;
+                }
+
+                if let Some(dedup) = dedup {
+                    write!(f, " dedup {}", dedup)?;
+                }
+
+                if let Some(group) = group {
+                    write!(f, " group {}", group)?;
+                }
+
+                match wait {
+                    Some(wait) => write!(f, " wait {}", wait),
+                    None => Ok(()),
+                }
+            }
+            Command::Dequeue(id, Reply::Value) => write!(f, "dequeue {}", id),
+            Command::Dequeue(id, Reply::Full) => write!(f, "dequeue {} full", id),
+            Command::PushFront(id, value, headers) => {
+                write!(f, "push_front {} {}", id, value)?;
+
+                match headers {
+                    Some(headers) => write!(f, " headers {}", headers),
+                    None => Ok(()),
+                }
+            }
+            Command::PopBack(id, Reply::Value) => write!(f, "pop_back {}", id),
+            Command::PopBack(id, Reply::Full) => write!(f, "pop_back {} full", id),
+            Command::Length(id) => write!(f, "length {}", id),
+            Command::Peek(id, Reply::Value) => write!(f, "peek {}", id),
+            Command::Peek(id, Reply::Full) => write!(f, "peek {} full", id),
+            Command::PeekBack(id, Reply::Value) => write!(f, "peek_back {}", id),
+            Command::PeekBack(id, Reply::Full) => write!(f, "peek_back {} full", id),
+            Command::Range(id, start, count) => write!(f, "range {} {} {}", id, start, count),
+            Command::Move(src, dst, None) => write!(f, "move {} {}", src, dst),
+            Command::Move(src, dst, Some(timeout)) => {
+                write!(f, "move {} {} wait {}", src, dst, timeout)
+            }
+            Command::Release(id, group) => write!(f, "release {} {}", id, group),
+            Command::Purge(id) => write!(f, "purge {}", id),
+            Command::Delete(id) => write!(f, "delete {}", id),
+            Command::Rename(src, dst) => write!(f, "rename {} {}", src, dst),
+            Command::Copy(src, dst) => write!(f, "copy {} {}", src, dst),
+            Command::Queues(None) => write!(f, "queues"),
+            Command::Queues(Some(pattern)) => write!(f, "queues {}", pattern),
+            Command::Scan(cursor, None) => write!(f, "scan {}", cursor),
+            Command::Scan(cursor, Some(pattern)) => write!(f, "scan {} {}", cursor, pattern),
+            Command::Info(id) => write!(f, "info {}", id),
+            Command::Open(id, value_type, mode) => {
+                write!(f, "open {}", id)?;
+
+                if let Some(value_type) = value_type {
+                    write!(f, " {}", value_type)?;
+                }
+
+                if let Some(mode) = mode {
+                    write!(f, " mode {}", mode)?;
+                }
+
+                Ok(())
+            }
+            Command::Configure(target, settings) => {
+                write!(f, "configure {}", target)?;
+
+                for setting in settings {
+                    write!(f, " {}", setting)?;
+                }
+
+                Ok(())
+            }
+            Command::Config(target) => write!(f, "config {}", target),
+            Command::Assert(cmd, v) => write!(f, "assert ({}) {}", cmd, v),
+            Command::AssertError(cmd) => write!(f, "assert error ({})", cmd),
+            Command::Let(id, v) => write!(f, "let {} = {}", id, v),
+            Command::Repeat(count, body) => {
+                write!(f, "repeat {} ", count)?;
+                write_block(f, body, indent)
+            }
+            Command::While(condition, body) => {
+                write!(f, "while {} ", condition)?;
+                write_block(f, body, indent)
+            }
+            Command::If(condition, then, otherwise) => {
+                write!(f, "if {} ", condition)?;
+                write_block(f, then, indent)?;
+
+                match otherwise.as_slice() {
+                    [] => Ok(()),
+                    [nested @ Command::If(..)] => {
+                        write!(f, " else ")?;
+                        nested.write_indented(f, indent)
+                    }
+                    otherwise => {
+                        write!(f, " else ")?;
+                        write_block(f, otherwise, indent)
+                    }
+                }
+            }
+            Command::Begin => write!(f, "begin"),
+            Command::Commit => write!(f, "commit"),
+            Command::Rollback => write!(f, "rollback"),
+            Command::Comment(text) => write!(f, "#{}", text),
+            Command::Noop => Ok(()),
+        }
+    }
+
     pub fn enqueue<Id: Into<Identifier>, V: Into<Value>>(id: Id, v: V) -> Self {
-        Self::Enqueue(id.into(), v.into())
+        Self::Enqueue {
+            queue: id.into(),
+            value: Expr::Value(v.into()),
+            headers: None,
+            dedup: None,
+            group: None,
+            wait: None,
+        }
     }
 
     pub fn dequeue<T: Into<Identifier>>(id: T) -> Self {
-        Self::Dequeue(id.into())
+        Self::Dequeue(id.into(), Reply::Value)
     }
 
     pub fn peek<T: Into<Identifier>>(id: T) -> Self {
-        Self::Peek(id.into())
+        Self::Peek(id.into(), Reply::Value)
     }
 
     pub fn length<T: Into<Identifier>>(id: T) -> Self {
         Self::Length(id.into())
     }
 }
+
+#[test]
+fn structured_value_display_test() {
+    let map: BTreeMap<String, Value> = vec![("k".to_string(), Value::from(vec![1u8, 255]))]
+        .into_iter()
+        .collect();
+    let list = Value::List(vec![
+        1.into(),
+        "a \"b\"".to_string().into(),
+        true.into(),
+        map.into(),
+    ]);
+
+    assert_eq!(
+        list.to_string(),
+        r#"[1, "a \"b\"", true, {k: b"\x01\xff"}]"#
+    );
+    assert_eq!(Value::String("a".into()).to_string(), "a");
+    assert_eq!(Literal(&"a".to_string().into()).to_string(), r#""a""#);
+    assert_eq!(Value::Float(1.0).to_string(), "1");
+    assert_eq!(Literal(&Value::Float(1.0)).to_string(), "1.0");
+}